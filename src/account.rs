@@ -0,0 +1,305 @@
+use chrono::Utc;
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{digest::Update, Digest};
+use worker::{D1Database, Env, Request, Response, Result};
+
+use crate::mailer::{Mail, MailSender, Mailer};
+use crate::types::{DurableObjectAugmentedMsg, Op};
+use crate::validation::{validate_email, validate_password};
+use crate::{forward_op_to_do, logging, JsValue};
+
+pub const EMAIL_VERIFICATION_TTL_SECS: i64 = 60 * 60 * 24;
+pub const PASSWORD_RESET_TTL_SECS: i64 = 60 * 60;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum TokenPurpose {
+    EmailVerification,
+    PasswordReset,
+}
+
+impl TokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenPurpose::EmailVerification => "EmailVerification",
+            TokenPurpose::PasswordReset => "PasswordReset",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AuthToken {
+    pub user_id: String,
+    pub email: String,
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(sha2::Sha256::new().chain(token.as_bytes()).finalize())
+}
+
+/// Create a single-use token for `user_id`, replacing any unused one with the
+/// same purpose. Only the hash is stored; the raw token goes into the mail.
+pub async fn issue_token(
+    d1: &D1Database,
+    user_id: &str,
+    email: &str,
+    purpose: TokenPurpose,
+) -> Result<String> {
+    let mut bytes = [0u8; 32];
+    thread_rng().fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+
+    let ttl = match purpose {
+        TokenPurpose::EmailVerification => EMAIL_VERIFICATION_TTL_SECS,
+        TokenPurpose::PasswordReset => PASSWORD_RESET_TTL_SECS,
+    };
+    let now = Utc::now().timestamp();
+
+    d1.prepare("DELETE FROM auth_tokens WHERE user_id = ? AND purpose = ? AND used_at IS NULL")
+        .bind(&[user_id.into(), purpose.as_str().into()])?
+        .run()
+        .await?;
+
+    d1.prepare(
+        "INSERT INTO auth_tokens (token_hash, user_id, purpose, email, created_at, expires_at)
+         VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&[
+        hash_token(&token).into(),
+        user_id.into(),
+        purpose.as_str().into(),
+        email.into(),
+        (now as f64).into(),
+        ((now + ttl) as f64).into(),
+    ])?
+    .run()
+    .await?;
+
+    Ok(token)
+}
+
+/// Look a token up without spending it. Returns `None` if it is unknown,
+/// expired, already used or issued for another purpose.
+pub async fn find_token(
+    d1: &D1Database,
+    token: &str,
+    purpose: TokenPurpose,
+) -> Result<Option<AuthToken>> {
+    d1.prepare(
+        "SELECT user_id, email FROM auth_tokens
+         WHERE token_hash = ? AND purpose = ? AND used_at IS NULL AND expires_at > ?",
+    )
+    .bind(&[
+        hash_token(token).into(),
+        purpose.as_str().into(),
+        (Utc::now().timestamp() as f64).into(),
+    ])?
+    .first::<AuthToken>(None)
+    .await
+}
+
+/// Spend a token. Returns false if another request already spent it, so
+/// concurrent redemptions can't both apply it.
+pub async fn claim_token(d1: &D1Database, token: &str) -> Result<bool> {
+    let changes = d1
+        .prepare("UPDATE auth_tokens SET used_at = ? WHERE token_hash = ? AND used_at IS NULL")
+        .bind(&[
+            (Utc::now().timestamp() as f64).into(),
+            hash_token(token).into(),
+        ])?
+        .run()
+        .await?
+        .meta()?
+        .and_then(|m| m.changes)
+        .unwrap_or(0);
+    Ok(changes == 1)
+}
+
+/// Make a claimed token usable again after what it was issued for failed
+pub async fn release_token(d1: &D1Database, token: &str) -> Result<()> {
+    d1.prepare("UPDATE auth_tokens SET used_at = NULL WHERE token_hash = ?")
+        .bind(&[hash_token(token).into()])?
+        .run()
+        .await?;
+    Ok(())
+}
+
+/// Claim a token and run its op on the player's DO. Returns false if the
+/// token was already claimed; if the op fails the token is released for a retry.
+async fn redeem_token(
+    d1: &D1Database,
+    env: &Env,
+    token: &str,
+    user_id: String,
+    op: Op,
+    rid: String,
+) -> Result<bool> {
+    if !claim_token(d1, token).await? {
+        return Ok(false);
+    }
+
+    let forwarded = forward_op_to_do(
+        env,
+        &DurableObjectAugmentedMsg {
+            user_id,
+            op,
+            request_id: rid,
+        },
+    )
+    .await;
+    if let Err(e) = forwarded {
+        release_token(d1, token).await?;
+        return Err(e);
+    }
+    Ok(true)
+}
+
+pub fn public_base_url(env: &Env) -> String {
+    env.var("PUBLIC_BASE_URL")
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| "http://localhost:8787".to_string())
+}
+
+/// Issue a verification token for a freshly set email and mail the link
+pub async fn send_verification_email(
+    d1: &D1Database,
+    env: &Env,
+    mailer: &Mailer,
+    user_id: &str,
+    email: &str,
+) -> Result<()> {
+    let token = issue_token(d1, user_id, email, TokenPurpose::EmailVerification).await?;
+    let link = format!("{}/api/verify_email?token={}", public_base_url(env), token);
+
+    mailer
+        .send(&Mail {
+            to: email.to_string(),
+            subject: "Verify your email".to_string(),
+            body: format!(
+                "Confirm this address for your account by opening {}\nThe link expires in 24 hours.",
+                link
+            ),
+        })
+        .await
+}
+
+// Handler for GET /api/verify_email?token=...
+pub async fn handle_verify_email(req: Request, env: &Env) -> Result<Response> {
+    let rid = logging::request_id(&req);
+    let d1 = env.d1("D1_DATABASE")?;
+
+    let url = req.url()?;
+    let Some(token) = url
+        .query_pairs()
+        .find(|(k, _)| k == "token")
+        .map(|(_, v)| v.into_owned())
+    else {
+        return Response::error("Missing token", 400);
+    };
+
+    let Some(AuthToken { user_id, email }) =
+        find_token(&d1, &token, TokenPurpose::EmailVerification).await?
+    else {
+        return Response::error("Invalid or expired token", 400);
+    };
+
+    let op = Op::MarkEmailVerified(email);
+    if !redeem_token(&d1, env, &token, user_id, op, rid).await? {
+        return Response::error("Invalid or expired token", 400);
+    }
+
+    Response::ok("Email verified")
+}
+
+#[derive(Deserialize)]
+struct ResetRequestBody {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct ResetConfirmBody {
+    token: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+struct UserIdRow {
+    user_id: String,
+}
+
+// Handler for POST /api/password_reset/request
+pub async fn handle_password_reset_request(mut req: Request, env: &Env) -> Result<Response> {
+    let rid = logging::request_id(&req);
+    let d1 = env.d1("D1_DATABASE")?;
+
+    let ResetRequestBody { email } = match req.json().await {
+        Ok(body) => body,
+        Err(_) => return Response::error("Invalid JSON", 400),
+    };
+    if let Err(reason) = validate_email(&email) {
+        return Response::error(reason, 400);
+    }
+
+    let mailer = Mailer::from_env(env);
+
+    // Only verified addresses can receive a reset link
+    let user = d1
+        .prepare(
+            "SELECT user_id FROM user_profile WHERE email = ? COLLATE NOCASE AND email_verified = 1",
+        )
+        .bind(&[JsValue::from(email.as_str())])?
+        .first::<UserIdRow>(None)
+        .await?;
+
+    if let Some(UserIdRow { user_id }) = user {
+        let token = issue_token(&d1, &user_id, &email, TokenPurpose::PasswordReset).await?;
+        let mail = Mail {
+            to: email.clone(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Use this code to choose a new password: {}\nIt expires in one hour. If you did not ask for a reset, ignore this mail.",
+                token
+            ),
+        };
+        if let Err(e) = mailer.send(&mail).await {
+            log_error!(&rid, "password_reset.mail_failed", "user_id" => user_id, "error" => e.to_string());
+            return Response::error("Failed to send email", 500);
+        }
+    }
+
+    // Same answer whether or not the address exists, so it can't be used to probe accounts
+    Response::from_json(&json!({
+        "status": "If this address belongs to a verified account, a reset email has been sent"
+    }))
+}
+
+// Handler for POST /api/password_reset/confirm
+pub async fn handle_password_reset_confirm(mut req: Request, env: &Env) -> Result<Response> {
+    let rid = logging::request_id(&req);
+    let d1 = env.d1("D1_DATABASE")?;
+
+    let ResetConfirmBody {
+        token,
+        new_password,
+    } = match req.json().await {
+        Ok(body) => body,
+        Err(_) => return Response::error("Invalid JSON", 400),
+    };
+    if let Err(reason) = validate_password(&new_password) {
+        return Response::error(reason, 400);
+    }
+
+    let Some(AuthToken { user_id, .. }) =
+        find_token(&d1, &token, TokenPurpose::PasswordReset).await?
+    else {
+        return Response::error("Invalid or expired token", 400);
+    };
+
+    let op = Op::ResetPassword(new_password);
+    if !redeem_token(&d1, env, &token, user_id, op, rid).await? {
+        return Response::error("Invalid or expired token", 400);
+    }
+
+    Response::ok("Password updated")
+}
@@ -0,0 +1,585 @@
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use worker::{D1Database, Env, Method, Request, Response, Result};
+
+use crate::daily_task::{self, DailyLinkInput};
+use crate::notification::{push_notification_to_user_do, NotificationType};
+use crate::quests::{self, QuestChainInput};
+use crate::types::{AdminOp, DurableObjectAugmentedMsg, Grant, Op, UserData, WsError};
+use crate::utils::is_registered;
+use crate::{forward_op_to_do, logging, login_guard, registry, JsValue};
+
+// Broadcast fan-out: DO calls in flight at once
+const BROADCAST_CONCURRENCY: usize = 20;
+const DEFAULT_AUDIT_LIMIT: usize = 50;
+const MAX_AUDIT_LIMIT: usize = 500;
+
+/// Ordered: each role can do everything the ones before it can
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AdminRole {
+    // Read-only: inspect players, links and the audit log
+    Viewer,
+    // Player safety: ban, unlock, force a sync
+    Moderator,
+    // Economy and content: edit state, grant/revoke, links, broadcasts
+    Owner,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct AdminIdentity {
+    pub name: String,
+    pub role: AdminRole,
+}
+
+/// Compare without bailing out at the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Resolve `Authorization: Bearer <token>` against the ADMIN_TOKENS secret
+/// (`{"<token>": {"name": "alice", "role": "Moderator"}}`). The single
+/// ADMIN_TOKEN secret still works and maps to an Owner named "admin".
+pub fn authenticate(req: &Request, env: &Env) -> Option<AdminIdentity> {
+    let header = req.headers().get("Authorization").ok().flatten()?;
+    let presented = header.strip_prefix("Bearer ")?;
+
+    let mut tokens: HashMap<String, AdminIdentity> = env
+        .secret("ADMIN_TOKENS")
+        .ok()
+        .and_then(|s| serde_json::from_str(&s.to_string()).ok())
+        .unwrap_or_default();
+    if let Ok(token) = env.secret("ADMIN_TOKEN") {
+        tokens.entry(token.to_string()).or_insert(AdminIdentity {
+            name: "admin".to_string(),
+            role: AdminRole::Owner,
+        });
+    }
+
+    // Check every entry so timing doesn't reveal which one matched
+    tokens
+        .into_iter()
+        .filter(|(token, _)| constant_time_eq(token.as_bytes(), presented.as_bytes()))
+        .map(|(_, identity)| identity)
+        .last()
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Ban {
+    pub user_id: String,
+    pub reason: String,
+    pub banned_by: String,
+    pub banned_at: i64,
+    pub expires_at: Option<i64>,
+}
+
+/// The ban currently in force for `user_id`, ignoring expired ones
+pub async fn active_ban(d1: &D1Database, user_id: &str) -> Result<Option<Ban>> {
+    let now = Utc::now().timestamp() as f64;
+    d1.prepare(
+        "SELECT user_id, reason, banned_by, banned_at, expires_at FROM banned_users
+         WHERE user_id = ? AND (expires_at IS NULL OR expires_at > ?)",
+    )
+    .bind(&[user_id.into(), now.into()])?
+    .first::<Ban>(None)
+    .await
+}
+
+/// 403 sent to a banned player trying to connect
+pub fn banned_response(ban: &Ban) -> Result<Response> {
+    let body = WsError {
+        error: "Banned".to_string(),
+        code: 403,
+        message: ban.reason.clone(),
+        retry_after_secs: ban
+            .expires_at
+            .map(|until| (until - Utc::now().timestamp()).max(0) as u64),
+    };
+    Ok(Response::from_json(&body)?.with_status(403))
+}
+
+async fn ban_user(d1: &D1Database, ban: &Ban) -> Result<()> {
+    d1.prepare(
+        "INSERT INTO banned_users (user_id, reason, banned_by, banned_at, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(user_id) DO UPDATE SET
+            reason = ?2, banned_by = ?3, banned_at = ?4, expires_at = ?5",
+    )
+    .bind(&[
+        ban.user_id.as_str().into(),
+        ban.reason.as_str().into(),
+        ban.banned_by.as_str().into(),
+        (ban.banned_at as f64).into(),
+        ban.expires_at
+            .map_or(JsValue::NULL, |t| JsValue::from(t as f64)),
+    ])?
+    .run()
+    .await?;
+    Ok(())
+}
+
+async fn unban_user(d1: &D1Database, user_id: &str) -> Result<bool> {
+    let removed = d1
+        .prepare("DELETE FROM banned_users WHERE user_id = ?")
+        .bind(&[user_id.into()])?
+        .run()
+        .await?
+        .meta()?
+        .and_then(|m| m.changes)
+        .unwrap_or(0);
+    Ok(removed > 0)
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub admin_name: String,
+    pub admin_role: String,
+    pub action: String,
+    pub target: Option<String>,
+    pub details: Option<String>,
+    pub status: u16,
+    pub created_at: i64,
+}
+
+async fn record_audit(
+    d1: &D1Database,
+    admin: &AdminIdentity,
+    action: &str,
+    target: Option<&str>,
+    details: &Value,
+    status: u16,
+) -> Result<()> {
+    d1.prepare(
+        "INSERT INTO admin_audit_log (admin_name, admin_role, action, target, details, status, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(&[
+        admin.name.as_str().into(),
+        format!("{:?}", admin.role).into(),
+        action.into(),
+        target.map_or(JsValue::NULL, JsValue::from),
+        logging::redact(details.clone()).to_string().into(),
+        status.into(),
+        (Utc::now().timestamp() as f64).into(),
+    ])?
+    .run()
+    .await?;
+    Ok(())
+}
+
+async fn list_audit(
+    d1: &D1Database,
+    target: Option<&str>,
+    limit: usize,
+) -> Result<Vec<AuditEntry>> {
+    d1.prepare(
+        "SELECT id, admin_name, admin_role, action, target, details, status, created_at
+         FROM admin_audit_log
+         WHERE ?1 IS NULL OR target = ?1
+         ORDER BY id DESC
+         LIMIT ?2",
+    )
+    .bind(&[target.map_or(JsValue::NULL, JsValue::from), limit.into()])?
+    .all()
+    .await?
+    .results::<AuditEntry>()
+}
+
+/// Every admin endpoint, parsed from method + path
+enum Action {
+    Inspect(String),
+    Replace(String),
+    Grant(String),
+    Revoke(String),
+    Sync(String),
+    Unlock(String),
+    Ban(String),
+    Unban(String),
+    ListLinks,
+    AddLink,
+    UpdateLink(i64),
+    DeleteLink(i64),
+    ListQuests,
+    UpsertQuest(String),
+    DeleteQuest(String),
+    Broadcast,
+    Audit,
+}
+
+impl Action {
+    fn parse(method: Method, path: &str) -> Option<Action> {
+        let segments: Vec<&str> = path
+            .trim_start_matches("/api/admin")
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        let action = match (method, segments.as_slice()) {
+            (Method::Get, ["users", id]) => Action::Inspect(id.to_string()),
+            (Method::Put, ["users", id]) => Action::Replace(id.to_string()),
+            (Method::Post, ["users", id, "grant"]) => Action::Grant(id.to_string()),
+            (Method::Post, ["users", id, "revoke"]) => Action::Revoke(id.to_string()),
+            (Method::Post, ["users", id, "sync"]) => Action::Sync(id.to_string()),
+            (Method::Post, ["users", id, "unlock"]) => Action::Unlock(id.to_string()),
+            (Method::Post, ["users", id, "ban"]) => Action::Ban(id.to_string()),
+            (Method::Post, ["users", id, "unban"]) => Action::Unban(id.to_string()),
+            (Method::Get, ["links"]) => Action::ListLinks,
+            (Method::Post, ["links"]) => Action::AddLink,
+            (Method::Put, ["links", id]) => Action::UpdateLink(id.parse().ok()?),
+            (Method::Delete, ["links", id]) => Action::DeleteLink(id.parse().ok()?),
+            (Method::Get, ["quests"]) => Action::ListQuests,
+            (Method::Put, ["quests", id]) => Action::UpsertQuest(id.to_string()),
+            (Method::Delete, ["quests", id]) => Action::DeleteQuest(id.to_string()),
+            (Method::Post, ["broadcast"]) => Action::Broadcast,
+            (Method::Get, ["audit"]) => Action::Audit,
+            _ => return None,
+        };
+        Some(action)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Inspect(_) => "inspect_user",
+            Action::Replace(_) => "replace_user_data",
+            Action::Grant(_) => "grant",
+            Action::Revoke(_) => "revoke",
+            Action::Sync(_) => "force_sync",
+            Action::Unlock(_) => "unlock",
+            Action::Ban(_) => "ban",
+            Action::Unban(_) => "unban",
+            Action::ListLinks => "list_links",
+            Action::AddLink => "add_link",
+            Action::UpdateLink(_) => "update_link",
+            Action::DeleteLink(_) => "delete_link",
+            Action::ListQuests => "list_quests",
+            Action::UpsertQuest(_) => "upsert_quest",
+            Action::DeleteQuest(_) => "delete_quest",
+            Action::Broadcast => "broadcast",
+            Action::Audit => "view_audit_log",
+        }
+    }
+
+    fn required_role(&self) -> AdminRole {
+        match self {
+            Action::Inspect(_) | Action::ListLinks | Action::ListQuests | Action::Audit => {
+                AdminRole::Viewer
+            }
+            Action::Sync(_) | Action::Unlock(_) | Action::Ban(_) | Action::Unban(_) => {
+                AdminRole::Moderator
+            }
+            Action::Replace(_)
+            | Action::Grant(_)
+            | Action::Revoke(_)
+            | Action::AddLink
+            | Action::UpdateLink(_)
+            | Action::DeleteLink(_)
+            | Action::UpsertQuest(_)
+            | Action::DeleteQuest(_)
+            | Action::Broadcast => AdminRole::Owner,
+        }
+    }
+
+    /// The player the action is about, if any
+    fn user_id(&self) -> Option<&str> {
+        match self {
+            Action::Inspect(id)
+            | Action::Replace(id)
+            | Action::Grant(id)
+            | Action::Revoke(id)
+            | Action::Sync(id)
+            | Action::Unlock(id)
+            | Action::Ban(id)
+            | Action::Unban(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// What the audit row is filed under: a user id, "link:<id>" or "quest:<id>"
+    fn audit_target(&self) -> Option<String> {
+        match self {
+            Action::UpdateLink(id) | Action::DeleteLink(id) => Some(format!("link:{}", id)),
+            Action::UpsertQuest(id) | Action::DeleteQuest(id) => Some(format!("quest:{}", id)),
+            _ => self.user_id().map(String::from),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BanBody {
+    reason: String,
+    // Omit for a permanent ban
+    duration_secs: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct BroadcastBody {
+    message: String,
+    // Everyone when omitted
+    user_ids: Option<Vec<String>>,
+}
+
+fn parse_body<T: DeserializeOwned>(body: &str) -> std::result::Result<T, Result<Response>> {
+    serde_json::from_str(body).map_err(|e| Response::error(format!("Invalid JSON: {}", e), 400))
+}
+
+fn query_param(req: &Request, key: &str) -> Option<String> {
+    req.url()
+        .ok()?
+        .query_pairs()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.into_owned())
+}
+
+// Handler for everything under /api/admin/
+pub async fn handle_admin(mut req: Request, env: &Env, rid: &str) -> Result<Response> {
+    let Some(admin) = authenticate(&req, env) else {
+        return Response::error("Unauthorized", 401);
+    };
+
+    let path = req.path();
+    let Some(action) = Action::parse(req.method(), &path) else {
+        return Response::error("Not Found", 404);
+    };
+
+    if admin.role < action.required_role() {
+        log_warn!(rid, "admin.forbidden", "admin" => admin.name, "action" => action.name());
+        return Response::error("Forbidden", 403);
+    }
+
+    let d1 = env.d1("D1_DATABASE")?;
+    let body = match req.method() {
+        Method::Get => String::new(),
+        _ => req.text().await.unwrap_or_default(),
+    };
+
+    let response = match run_action(&action, &req, &body, &admin, env, &d1, rid).await {
+        Ok(response) => response,
+        Err(e) => {
+            log_error!(rid, "admin.action_failed", "admin" => admin.name, "action" => action.name(), "error" => e.to_string());
+            Response::error("Internal Server Error", 500)?
+        }
+    };
+
+    // Reading the audit log is the one thing that doesn't add to it
+    if !matches!(action, Action::Audit) {
+        let details = serde_json::from_str::<Value>(&body).unwrap_or(Value::Null);
+        if let Err(e) = record_audit(
+            &d1,
+            &admin,
+            action.name(),
+            action.audit_target().as_deref(),
+            &details,
+            response.status_code(),
+        )
+        .await
+        {
+            log_error!(rid, "admin.audit_failed", "admin" => admin.name, "action" => action.name(), "error" => e.to_string());
+        }
+    }
+
+    log_info!(rid, "admin.action", "admin" => admin.name, "action" => action.name(), "target" => action.audit_target(), "status" => response.status_code());
+    Ok(response)
+}
+
+async fn run_action(
+    action: &Action,
+    req: &Request,
+    body: &str,
+    admin: &AdminIdentity,
+    env: &Env,
+    d1: &D1Database,
+    rid: &str,
+) -> Result<Response> {
+    if let Some(user_id) = action.user_id() {
+        // Unlock and unban must still work on rows left behind by a deleted account
+        let needs_account = !matches!(action, Action::Unlock(_) | Action::Unban(_));
+        if needs_account && !is_registered(d1, user_id).await {
+            return Response::error("User not found", 404);
+        }
+    }
+
+    match action {
+        Action::Inspect(user_id) => forward_admin_op(env, user_id, AdminOp::Inspect, rid).await,
+        Action::Replace(user_id) => {
+            let new_data: UserData = match parse_body(body) {
+                Ok(data) => data,
+                Err(response) => return response,
+            };
+            forward_admin_op(env, user_id, AdminOp::Replace(Box::new(new_data)), rid).await
+        }
+        Action::Grant(user_id) | Action::Revoke(user_id) => {
+            let grant: Grant = match parse_body(body) {
+                Ok(grant) => grant,
+                Err(response) => return response,
+            };
+            let op = if matches!(action, Action::Grant(_)) {
+                AdminOp::Grant(grant)
+            } else {
+                AdminOp::Revoke(grant)
+            };
+            forward_admin_op(env, user_id, op, rid).await
+        }
+        Action::Sync(user_id) => {
+            forward_op_to_do(
+                env,
+                &DurableObjectAugmentedMsg {
+                    user_id: user_id.clone(),
+                    op: Op::SyncData,
+                    request_id: rid.to_string(),
+                },
+            )
+            .await
+        }
+        Action::Unlock(user_id) => {
+            let was_locked = login_guard::clear(d1, &login_guard::account_key(user_id)).await?;
+            Response::from_json(&json!({
+                "user_id": user_id,
+                "cleared": was_locked
+            }))
+        }
+        Action::Ban(user_id) => {
+            let BanBody {
+                reason,
+                duration_secs,
+            } = match parse_body(body) {
+                Ok(ban) => ban,
+                Err(response) => return response,
+            };
+            if duration_secs.is_some_and(|secs| secs <= 0) {
+                return Response::error("duration_secs must be positive", 400);
+            }
+
+            let now = Utc::now().timestamp();
+            let ban = Ban {
+                user_id: user_id.clone(),
+                reason,
+                banned_by: admin.name.clone(),
+                banned_at: now,
+                expires_at: duration_secs.map(|secs| now + secs),
+            };
+            ban_user(d1, &ban).await?;
+            Response::from_json(&ban)
+        }
+        Action::Unban(user_id) => {
+            let removed = unban_user(d1, user_id).await?;
+            Response::from_json(&json!({
+                "user_id": user_id,
+                "unbanned": removed
+            }))
+        }
+        Action::ListLinks => Response::from_json(&daily_task::list_links(d1).await?),
+        Action::AddLink | Action::UpdateLink(_) => {
+            let input: DailyLinkInput = match parse_body(body) {
+                Ok(input) => input,
+                Err(response) => return response,
+            };
+            if let Err(reason) = input.validate() {
+                return Response::error(reason, 400);
+            }
+
+            let id = match action {
+                Action::UpdateLink(id) => Some(*id),
+                _ => None,
+            };
+            if daily_task::url_taken(d1, &input.url, id).await? {
+                return Response::error("A link with this url already exists", 409);
+            }
+
+            let link = match id {
+                Some(id) => daily_task::update_link(d1, id, &input).await?,
+                None => daily_task::insert_link(d1, &input).await?,
+            };
+            match link {
+                Some(link) => Response::from_json(&link),
+                None => Response::error("Link not found", 404),
+            }
+        }
+        Action::DeleteLink(id) => {
+            if daily_task::delete_link(d1, *id).await? {
+                Response::ok("Link deleted")
+            } else {
+                Response::error("Link not found", 404)
+            }
+        }
+        Action::ListQuests => Response::from_json(&quests::list_chains(d1).await?),
+        Action::UpsertQuest(id) => {
+            if let Err(reason) = quests::validate_quest_id(id) {
+                return Response::error(reason, 400);
+            }
+            let input: QuestChainInput = match parse_body(body) {
+                Ok(input) => input,
+                Err(response) => return response,
+            };
+            if let Err(reason) = input.validate() {
+                return Response::error(reason, 400);
+            }
+            Response::from_json(&quests::upsert_chain(d1, id, &input).await?)
+        }
+        Action::DeleteQuest(id) => {
+            if quests::delete_chain(d1, id).await? {
+                Response::ok("Quest deleted")
+            } else {
+                Response::error("Quest not found", 404)
+            }
+        }
+        Action::Broadcast => {
+            let BroadcastBody { message, user_ids } = match parse_body(body) {
+                Ok(broadcast) => broadcast,
+                Err(response) => return response,
+            };
+            if message.trim().is_empty() {
+                return Response::error("Message must not be empty", 400);
+            }
+            let user_ids = match user_ids {
+                Some(ids) => ids,
+                None => registry::get_all_user_ids(d1).await?,
+            };
+            broadcast(env, &user_ids, &message, rid).await
+        }
+        Action::Audit => {
+            let limit = query_param(req, "limit")
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(DEFAULT_AUDIT_LIMIT)
+                .min(MAX_AUDIT_LIMIT);
+            let target = query_param(req, "target");
+            Response::from_json(&list_audit(d1, target.as_deref(), limit).await?)
+        }
+    }
+}
+
+async fn forward_admin_op(env: &Env, user_id: &str, op: AdminOp, rid: &str) -> Result<Response> {
+    forward_op_to_do(
+        env,
+        &DurableObjectAugmentedMsg {
+            user_id: user_id.to_string(),
+            op: Op::Admin(op),
+            request_id: rid.to_string(),
+        },
+    )
+    .await
+}
+
+async fn broadcast(env: &Env, user_ids: &[String], message: &str, rid: &str) -> Result<Response> {
+    let failed: Vec<String> = stream::iter(user_ids)
+        .map(|user_id| async move {
+            push_notification_to_user_do(env, user_id, NotificationType::System, message, None)
+                .await
+                .err()
+                .map(|e| {
+                    log_warn!(rid, "admin.broadcast_failed", "user_id" => user_id, "error" => e.to_string());
+                    user_id.clone()
+                })
+        })
+        .buffer_unordered(BROADCAST_CONCURRENCY)
+        .filter_map(|failed| async move { failed })
+        .collect()
+        .await;
+
+    Response::from_json(&json!({
+        "sent": user_ids.len() - failed.len(),
+        "failed": failed
+    }))
+}
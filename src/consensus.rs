@@ -0,0 +1,454 @@
+// Scores a datapoint once enough players have labeled it: each label is
+// compared against the majority, low-agreement labels are flagged and the rest
+// ranked, and the outcome is paid out through `performance_outcomes`.
+use chrono::Utc;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use worker::{D1Database, Env, Result};
+
+use crate::labeling_client::LabelRequest;
+use crate::notification::{
+    deliver_task_reward, performance_outcomes, FailedDelivery, TaskResultInput,
+};
+use crate::types::{LabelSubmission, PreLabel, PreLabelVerdict};
+use crate::video_pool;
+
+// Labels needed before a datapoint is scored, unless CONSENSUS_MIN_SUBMISSIONS says otherwise
+pub const DEFAULT_MIN_SUBMISSIONS: usize = 5;
+
+// Below this agreement with the consensus a label is flagged
+pub const OUTLIER_AGREEMENT: f64 = 0.5;
+
+// Map positions this close to the consensus position count as agreeing
+pub const MAP_AGREEMENT_KM: f64 = 1.0;
+
+// Cron retries of a failed task reward before it is left in pending_deliveries for an admin
+const MAX_DELIVERY_ATTEMPTS: usize = 20;
+
+// Queued deliveries retried per cron run
+const DELIVERY_RETRY_BATCH: usize = 50;
+
+const VERDICT_WEIGHT: f64 = 0.4;
+const KEYWORDS_WEIGHT: f64 = 0.4;
+const MAP_WEIGHT: f64 = 0.2;
+
+/// Who decided a datapoint's result
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResultSource {
+    Consensus,
+    // POST /api/notify_task_result
+    Override,
+}
+
+impl ResultSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResultSource::Consensus => "consensus",
+            ResultSource::Override => "override",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StoredLabel {
+    pub user_id: String,
+    pub label: LabelSubmission,
+    pub corrected_label: PreLabel,
+    pub submitted_at: i64,
+}
+
+#[derive(Deserialize)]
+struct LabelRow {
+    user_id: String,
+    label: String,
+    corrected_label: String,
+    submitted_at: i64,
+}
+
+pub fn min_submissions(env: &Env) -> usize {
+    env.var("CONSENSUS_MIN_SUBMISSIONS")
+        .ok()
+        .and_then(|v| v.to_string().parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MIN_SUBMISSIONS)
+        .max(2)
+}
+
+fn keyword_set(label: &PreLabel) -> HashSet<String> {
+    label
+        .keywords
+        .iter()
+        .map(|kw| kw.trim().to_lowercase())
+        .collect()
+}
+
+fn parse_position(position: &str) -> Option<(f64, f64)> {
+    let (lat, lng) = position.split_once(',')?;
+    let (lat, lng) = (
+        lat.trim().parse::<f64>().ok()?,
+        lng.trim().parse::<f64>().ok()?,
+    );
+    (lat.is_finite() && lng.is_finite()).then_some((lat, lng))
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+// Equirectangular approximation, plenty at the distances that matter here
+fn distance_km(a: (f64, f64), b: (f64, f64)) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+    let mean_lat = ((a.0 + b.0) / 2.0).to_radians();
+    let x = (b.1 - a.1).to_radians() * mean_lat.cos();
+    let y = (b.0 - a.0).to_radians();
+    (x * x + y * y).sqrt() * EARTH_RADIUS_KM
+}
+
+fn jaccard(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(b).count() as f64 / union as f64
+}
+
+/// Agreement in [0, 1] of every label with the consensus, keyed by user id
+pub fn agreement_scores(labels: &[StoredLabel]) -> HashMap<String, f64> {
+    let half = labels.len() as f64 / 2.0;
+
+    // Ties keep the prelabel
+    let rejections = labels
+        .iter()
+        .filter(|l| l.label.verdict == PreLabelVerdict::Reject)
+        .count();
+    let majority_verdict = if rejections as f64 > half {
+        PreLabelVerdict::Reject
+    } else {
+        PreLabelVerdict::Accept
+    };
+
+    let keyword_sets: Vec<HashSet<String>> = labels
+        .iter()
+        .map(|l| keyword_set(&l.corrected_label))
+        .collect();
+    let mut keyword_votes: HashMap<&String, usize> = HashMap::new();
+    for kw in keyword_sets.iter().flatten() {
+        *keyword_votes.entry(kw).or_default() += 1;
+    }
+    let consensus_keywords: HashSet<String> = keyword_votes
+        .into_iter()
+        .filter(|(_, votes)| *votes as f64 > half)
+        .map(|(kw, _)| kw.clone())
+        .collect();
+
+    let positions: Vec<Option<(f64, f64)>> = labels
+        .iter()
+        .map(|l| parse_position(&l.corrected_label.map_position))
+        .collect();
+    let known: Vec<(f64, f64)> = positions.iter().flatten().copied().collect();
+    let consensus_position = (!known.is_empty()).then(|| {
+        (
+            median(known.iter().map(|p| p.0).collect()),
+            median(known.iter().map(|p| p.1).collect()),
+        )
+    });
+
+    labels
+        .iter()
+        .zip(keyword_sets.iter().zip(positions))
+        .map(|(label, (keywords, position))| {
+            let mut score = VERDICT_WEIGHT * (label.label.verdict == majority_verdict) as u8 as f64
+                + KEYWORDS_WEIGHT * jaccard(keywords, &consensus_keywords);
+            let mut weight = VERDICT_WEIGHT + KEYWORDS_WEIGHT;
+
+            if let Some(consensus_position) = consensus_position {
+                let close = position
+                    .is_some_and(|p| distance_km(p, consensus_position) <= MAP_AGREEMENT_KM);
+                score += MAP_WEIGHT * close as u8 as f64;
+                weight += MAP_WEIGHT;
+            }
+
+            (label.user_id.clone(), score / weight)
+        })
+        .collect()
+}
+
+/// Rank every labeler by agreement (earlier submissions win ties) and flag
+/// the ones below `OUTLIER_AGREEMENT`
+pub fn compute_consensus(datapoint_id: &str, labels: &[StoredLabel]) -> TaskResultInput {
+    let scores = agreement_scores(labels);
+    let score = |user_id: &str| scores.get(user_id).copied().unwrap_or(0.0);
+
+    let mut ranked: Vec<&StoredLabel> = labels.iter().collect();
+    ranked.sort_by(|a, b| {
+        score(&b.user_id)
+            .total_cmp(&score(&a.user_id))
+            .then(a.submitted_at.cmp(&b.submitted_at))
+    });
+
+    TaskResultInput {
+        player_ranking: ranked.iter().map(|l| l.user_id.clone()).collect(),
+        flagged_players: ranked
+            .iter()
+            .filter(|l| score(&l.user_id) < OUTLIER_AGREEMENT)
+            .map(|l| l.user_id.clone())
+            .collect(),
+        datapoint_id: datapoint_id.to_string(),
+        difficulty: None,
+    }
+}
+
+/// Store a player's label. A player's first label for a datapoint is the one that counts.
+pub async fn record_label(d1: &D1Database, user_id: &str, request: &LabelRequest) -> Result<()> {
+    d1.prepare(
+        "INSERT OR IGNORE INTO label_submissions (datapoint_id, user_id, label, corrected_label, submitted_at)
+         VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(&[
+        request.datapoint_id.as_str().into(),
+        user_id.into(),
+        serde_json::to_string(&request.label)?.into(),
+        serde_json::to_string(&request.corrected_label)?.into(),
+        (Utc::now().timestamp() as f64).into(),
+    ])?
+    .run()
+    .await?;
+
+    Ok(())
+}
+
+pub async fn load_labels(d1: &D1Database, datapoint_id: &str) -> Result<Vec<StoredLabel>> {
+    let rows = d1
+        .prepare(
+            "SELECT user_id, label, corrected_label, submitted_at
+             FROM label_submissions WHERE datapoint_id = ? ORDER BY submitted_at",
+        )
+        .bind(&[datapoint_id.into()])?
+        .all()
+        .await?
+        .results::<LabelRow>()?;
+
+    // A row that no longer parses is left out of scoring rather than failing it
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(StoredLabel {
+                label: serde_json::from_str(&row.label).ok()?,
+                corrected_label: serde_json::from_str(&row.corrected_label).ok()?,
+                user_id: row.user_id,
+                submitted_at: row.submitted_at,
+            })
+        })
+        .collect())
+}
+
+pub async fn is_resolved(d1: &D1Database, datapoint_id: &str) -> Result<bool> {
+    let row = d1
+        .prepare("SELECT 1 AS resolved FROM datapoint_results WHERE datapoint_id = ?")
+        .bind(&[datapoint_id.into()])?
+        .first::<usize>(Some("resolved"))
+        .await?;
+    Ok(row.is_some())
+}
+
+#[derive(Deserialize)]
+struct ResultRow {
+    source: String,
+    player_ranking: String,
+    flagged_players: String,
+}
+
+/// The result a datapoint was resolved with, if any
+pub async fn stored_result(
+    d1: &D1Database,
+    datapoint_id: &str,
+) -> Result<Option<(ResultSource, TaskResultInput)>> {
+    let row = d1
+        .prepare(
+            "SELECT source, player_ranking, flagged_players FROM datapoint_results WHERE datapoint_id = ?",
+        )
+        .bind(&[datapoint_id.into()])?
+        .first::<ResultRow>(None)
+        .await?;
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let source = if row.source == ResultSource::Override.as_str() {
+        ResultSource::Override
+    } else {
+        ResultSource::Consensus
+    };
+    Ok(Some((
+        source,
+        TaskResultInput {
+            player_ranking: serde_json::from_str(&row.player_ranking)?,
+            flagged_players: serde_json::from_str(&row.flagged_players)?,
+            datapoint_id: datapoint_id.to_string(),
+            difficulty: None,
+        },
+    )))
+}
+
+/// Record the datapoint's result. Returns false if it was already decided, so
+/// rewards are only paid once even when two resolutions race.
+pub async fn claim_result(
+    d1: &D1Database,
+    result: &TaskResultInput,
+    source: ResultSource,
+) -> Result<bool> {
+    let claimed = d1
+        .prepare(
+            "INSERT OR IGNORE INTO datapoint_results (datapoint_id, source, player_ranking, flagged_players, resolved_at)
+             VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&[
+            result.datapoint_id.as_str().into(),
+            source.as_str().into(),
+            serde_json::to_string(&result.player_ranking)?.into(),
+            serde_json::to_string(&result.flagged_players)?.into(),
+            (Utc::now().timestamp() as f64).into(),
+        ])?
+        .run()
+        .await?
+        .meta()?
+        .and_then(|m| m.changes)
+        .unwrap_or(0);
+
+    Ok(claimed > 0)
+}
+
+/// Score the datapoint if it has enough labels and nobody decided it yet.
+/// `Some` means the caller now owns paying out the result.
+pub async fn resolve_if_ready(
+    d1: &D1Database,
+    env: &Env,
+    datapoint_id: &str,
+) -> Result<Option<TaskResultInput>> {
+    if is_resolved(d1, datapoint_id).await? {
+        return Ok(None);
+    }
+
+    let labels = load_labels(d1, datapoint_id).await?;
+    if labels.len() < min_submissions(env) {
+        return Ok(None);
+    }
+
+    let mut result = compute_consensus(datapoint_id, &labels);
+    result.difficulty = video_pool::difficulty(d1, datapoint_id).await?;
+    if !claim_result(d1, &result, ResultSource::Consensus).await? {
+        return Ok(None);
+    }
+    Ok(Some(result))
+}
+
+/// Queue the players a task result could not be delivered to, for the cron to retry
+pub async fn queue_failed_deliveries(
+    d1: &D1Database,
+    datapoint_id: &str,
+    failed: &[FailedDelivery],
+) -> Result<()> {
+    let now = Utc::now().timestamp() as f64;
+    for delivery in failed {
+        d1.prepare(
+            "INSERT INTO pending_deliveries (datapoint_id, user_id, attempts, last_error, queued_at)
+             VALUES (?, ?, 0, ?, ?)
+             ON CONFLICT (datapoint_id, user_id) DO UPDATE SET last_error = excluded.last_error",
+        )
+        .bind(&[
+            datapoint_id.into(),
+            delivery.user_id.as_str().into(),
+            delivery.error.as_str().into(),
+            now.into(),
+        ])?
+        .run()
+        .await?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct PendingDelivery {
+    datapoint_id: String,
+    user_id: String,
+}
+
+/// Redeliver queued task rewards from the stored results. Run by the cron;
+/// reward keys make a delivery the player already got a no-op.
+pub async fn retry_pending_deliveries(d1: &D1Database, env: &Env, rid: &str) -> Result<()> {
+    let pending = d1
+        .prepare(
+            "SELECT datapoint_id, user_id FROM pending_deliveries
+             WHERE attempts < ? ORDER BY queued_at LIMIT ?",
+        )
+        .bind(&[
+            (MAX_DELIVERY_ATTEMPTS as f64).into(),
+            (DELIVERY_RETRY_BATCH as f64).into(),
+        ])?
+        .all()
+        .await?
+        .results::<PendingDelivery>()?;
+
+    let mut results: HashMap<String, Option<TaskResultInput>> = HashMap::new();
+    let mut delivered = 0;
+    let mut failed = 0;
+    for delivery in pending {
+        if !results.contains_key(&delivery.datapoint_id) {
+            let mut result = stored_result(d1, &delivery.datapoint_id)
+                .await?
+                .map(|(_, result)| result);
+            if let Some(result) = result.as_mut() {
+                result.difficulty = video_pool::difficulty(d1, &delivery.datapoint_id).await?;
+            }
+            results.insert(delivery.datapoint_id.clone(), result);
+        }
+
+        // Nothing left to deliver if the result or the player's ranking is gone
+        let outcome = results[&delivery.datapoint_id].as_ref().and_then(|result| {
+            performance_outcomes(result)
+                .into_iter()
+                .find(|outcome| outcome.user_id == delivery.user_id)
+        });
+        let outcome = match outcome {
+            Some(outcome) => deliver_task_reward(env, &delivery.datapoint_id, outcome)
+                .await
+                .map(|_| ()),
+            None => Ok(()),
+        };
+
+        match outcome {
+            Ok(()) => {
+                delivered += 1;
+                d1.prepare("DELETE FROM pending_deliveries WHERE datapoint_id = ? AND user_id = ?")
+                    .bind(&[
+                        delivery.datapoint_id.as_str().into(),
+                        delivery.user_id.as_str().into(),
+                    ])?
+                    .run()
+                    .await?;
+            }
+            Err(e) => {
+                failed += 1;
+                d1.prepare(
+                    "UPDATE pending_deliveries SET attempts = attempts + 1, last_error = ?
+                     WHERE datapoint_id = ? AND user_id = ?",
+                )
+                .bind(&[
+                    e.to_string().into(),
+                    delivery.datapoint_id.as_str().into(),
+                    delivery.user_id.as_str().into(),
+                ])?
+                .run()
+                .await?;
+            }
+        }
+    }
+
+    log_info!(rid, "consensus.deliveries_retried", "delivered" => delivered, "failed" => failed);
+    Ok(())
+}
@@ -1,9 +1,12 @@
 // daily_task.rs
-use once_cell::sync::Lazy;
-use rand::{seq::SliceRandom, Rng};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::sync::Mutex;
+use worker::{D1Database, Env, Result};
+
+use crate::types::DailyProgress;
+use crate::JsValue;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum SocialPlatform {
@@ -16,61 +19,357 @@ pub enum SocialPlatform {
     Facebook,
 }
 
+impl SocialPlatform {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SocialPlatform::YouTube => "YouTube",
+            SocialPlatform::Twitter => "Twitter",
+            SocialPlatform::LinkedIn => "LinkedIn",
+            SocialPlatform::Instagram => "Instagram",
+            SocialPlatform::Telegram => "Telegram",
+            SocialPlatform::Discord => "Discord",
+            SocialPlatform::Facebook => "Facebook",
+        }
+    }
+}
+
+// Social score for finishing the merge / annotate / power-up daily target
+pub const DAILY_COUNTER_REWARD: usize = 2;
+
+/// The daily tasks tracked as (done, target, completed) in `DailyProgress`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DailyCounter {
+    Merge,
+    Annotate,
+    PowerUps,
+}
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Timezones are offsets from UTC, UTC-12:00 to UTC+14:00
+pub const MIN_UTC_OFFSET_MINUTES: i32 = -12 * 60;
+pub const MAX_UTC_OFFSET_MINUTES: i32 = 14 * 60;
+
+// Each timezone change can shift the player's day, so it is rate limited
+pub const TIMEZONE_CHANGE_COOLDOWN_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// The player's calendar date at `now` (unix seconds), e.g. "2025-03-14".
+/// ISO dates compare correctly as strings.
+pub fn local_day(now: i64, utc_offset_minutes: i32) -> String {
+    DateTime::from_timestamp(now + utc_offset_minutes as i64 * 60, 0)
+        .unwrap_or_default()
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+/// Unix time of the player's next local midnight
+pub fn next_local_midnight(now: i64, utc_offset_minutes: i32) -> i64 {
+    let offset = utc_offset_minutes as i64 * 60;
+    ((now + offset).div_euclid(SECS_PER_DAY) + 1) * SECS_PER_DAY - offset
+}
+
+/// Social score taken at rollover from a player who finished fewer than
+/// `min_completed` of the previous day's tasks. DAILY_PENALTY_MIN_COMPLETED
+/// and DAILY_PENALTY_SOCIAL_SCORE override the defaults; 0 disables it.
+pub struct IncompletePenalty {
+    pub min_completed: usize,
+    pub social_score: usize,
+}
+
+impl IncompletePenalty {
+    pub fn from_env(env: &Env) -> Self {
+        let var = |name: &str, default: usize| {
+            env.var(name)
+                .ok()
+                .and_then(|v| v.to_string().parse::<usize>().ok())
+                .unwrap_or(default)
+        };
+        IncompletePenalty {
+            min_completed: var("DAILY_PENALTY_MIN_COMPLETED", 3),
+            social_score: var("DAILY_PENALTY_SOCIAL_SCORE", 5),
+        }
+    }
+
+    /// Penalty owed for `previous`, the day being rolled over
+    pub fn penalty_for(&self, previous: &DailyProgress) -> usize {
+        if previous.day.is_some() && previous.total_completed < self.min_completed {
+            self.social_score
+        } else {
+            0
+        }
+    }
+}
+
+// Social score for visiting a daily link, also used for links handed out
+// before rewards were configurable
+pub const DEFAULT_LINK_REWARD: usize = 2;
+
+fn default_link_reward() -> usize {
+    DEFAULT_LINK_REWARD
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Links {
     pub url: String,
     pub platform: SocialPlatform,
     pub visited: bool,
+    #[serde(default = "default_link_reward")]
+    pub reward: usize,
+    // Signed /r/{token} redirect; opening it is what marks the link visited
+    #[serde(default)]
+    pub click_url: Option<String>,
 }
 
-// Global mutable container of all available links
-static LINK_STORAGE: Lazy<Mutex<Vec<Links>>> = Lazy::new(|| {
-    Mutex::new(vec![
-        Links {
-            url: "https://youtube.com/campaign1".into(),
-            platform: SocialPlatform::YouTube,
-            visited: false,
-        },
-        Links {
-            url: "https://twitter.com/promo123".into(),
-            platform: SocialPlatform::Twitter,
-            visited: false,
-        },
-        Links {
-            url: "https://linkedin.com/offer".into(),
-            platform: SocialPlatform::LinkedIn,
-            visited: false,
-        },
-        Links {
-            url: "https://discord.gg/community".into(),
-            platform: SocialPlatform::Discord,
-            visited: false,
-        },
-    ])
-});
+/// A campaign link in the `daily_links` pool
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct DailyLink {
+    pub id: i64,
+    pub url: String,
+    pub platform: SocialPlatform,
+    pub reward: usize,
+    // Relative chance of being drawn; 0 keeps the link but never hands it out
+    pub weight: f64,
+    pub starts_at: Option<i64>,
+    pub ends_at: Option<i64>,
+    #[serde(deserialize_with = "bool_from_int")]
+    pub enabled: bool,
+    pub created_at: i64,
+}
+
+impl DailyLink {
+    pub fn is_live(&self, now: i64) -> bool {
+        self.enabled
+            && self.weight > 0.0
+            && self.starts_at.is_none_or(|start| start <= now)
+            && self.ends_at.is_none_or(|end| now < end)
+    }
+}
+
+// D1 hands booleans back as 0 / 1
+fn bool_from_int<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<bool, D::Error> {
+    Ok(f64::deserialize(deserializer)? != 0.0)
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Body of the admin create / update link routes
+#[derive(Clone, Debug, Deserialize)]
+pub struct DailyLinkInput {
+    pub url: String,
+    pub platform: SocialPlatform,
+    #[serde(default = "default_link_reward")]
+    pub reward: usize,
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    pub starts_at: Option<i64>,
+    pub ends_at: Option<i64>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+impl DailyLinkInput {
+    pub fn validate(&self) -> std::result::Result<(), &'static str> {
+        if !(self.url.starts_with("https://") || self.url.starts_with("http://")) {
+            return Err("url must start with http:// or https://");
+        }
+        if !self.weight.is_finite() || self.weight < 0.0 {
+            return Err("weight must be a non-negative number");
+        }
+        if let (Some(start), Some(end)) = (self.starts_at, self.ends_at) {
+            if end <= start {
+                return Err("ends_at must be after starts_at");
+            }
+        }
+        Ok(())
+    }
+
+    fn bind_values(&self) -> [JsValue; 7] {
+        let optional_time = |t: Option<i64>| t.map_or(JsValue::NULL, |t| JsValue::from(t as f64));
+        [
+            self.url.as_str().into(),
+            self.platform.as_str().into(),
+            self.reward.into(),
+            self.weight.into(),
+            optional_time(self.starts_at),
+            optional_time(self.ends_at),
+            (self.enabled as u8).into(),
+        ]
+    }
+}
+
+const LINK_COLUMNS: &str =
+    "id, url, platform, reward, weight, starts_at, ends_at, enabled, created_at";
+
+// How long an isolate reuses the enabled links before asking D1 again
+const LINK_CACHE_TTL_SECS: i64 = 60;
+
+struct LinkCache {
+    fetched_at: i64,
+    links: Vec<DailyLink>,
+}
+
+// Enabled links only; the active window is checked when sampling so a cached
+// list stays correct as campaigns start and end
+static LINK_CACHE: Mutex<Option<LinkCache>> = Mutex::new(None);
+
+/// Drop this isolate's cached pool (other isolates catch up within the TTL)
+pub fn invalidate_link_cache() {
+    if let Ok(mut cache) = LINK_CACHE.lock() {
+        *cache = None;
+    }
+}
+
+async fn enabled_links(d1: &D1Database) -> Result<Vec<DailyLink>> {
+    let now = Utc::now().timestamp();
+    if let Ok(cache) = LINK_CACHE.lock() {
+        if let Some(cache) = cache.as_ref() {
+            if now - cache.fetched_at < LINK_CACHE_TTL_SECS {
+                return Ok(cache.links.clone());
+            }
+        }
+    }
+
+    let links = d1
+        .prepare(format!(
+            "SELECT {} FROM daily_links WHERE enabled = 1",
+            LINK_COLUMNS
+        ))
+        .all()
+        .await?
+        .results::<DailyLink>()?;
+
+    if let Ok(mut cache) = LINK_CACHE.lock() {
+        *cache = Some(LinkCache {
+            fetched_at: now,
+            links: links.clone(),
+        });
+    }
+    Ok(links)
+}
+
+/// Draw up to `count` distinct live links, weighted by their `weight`
+pub async fn get_random_links(d1: &D1Database, count: usize) -> Result<Vec<Links>> {
+    let now = Utc::now().timestamp();
+    let live: Vec<DailyLink> = enabled_links(d1)
+        .await?
+        .into_iter()
+        .filter(|link| link.is_live(now))
+        .collect();
 
-/// Fetch X random links from the global pool
-pub fn get_random_links(count: usize) -> Vec<Links> {
-    let storage = LINK_STORAGE.lock().unwrap();
     let mut rng = rand::thread_rng();
-    storage.choose_multiple(&mut rng, count).cloned().collect()
+    let chosen = live
+        .choose_multiple_weighted(&mut rng, count.min(live.len()), |link| link.weight)
+        .map_err(|e| worker::Error::RustError(format!("Link sampling failed: {}", e)))?;
+
+    Ok(chosen
+        .map(|link| Links {
+            url: link.url.clone(),
+            platform: link.platform.clone(),
+            visited: false,
+            reward: link.reward,
+            click_url: None,
+        })
+        .collect())
+}
+
+pub async fn list_links(d1: &D1Database) -> Result<Vec<DailyLink>> {
+    d1.prepare(format!(
+        "SELECT {} FROM daily_links ORDER BY id",
+        LINK_COLUMNS
+    ))
+    .all()
+    .await?
+    .results::<DailyLink>()
+}
+
+pub async fn url_taken(d1: &D1Database, url: &str, except_id: Option<i64>) -> Result<bool> {
+    let row = d1
+        .prepare("SELECT 1 AS taken FROM daily_links WHERE url = ? AND id IS NOT ?")
+        .bind(&[
+            url.into(),
+            except_id.map_or(JsValue::NULL, |id| JsValue::from(id as f64)),
+        ])?
+        .first::<usize>(Some("taken"))
+        .await?;
+    Ok(row.is_some())
 }
 
-/// Insert a new link into the global storage
-pub fn insert_link(new_link: Links) {
-    let mut storage = LINK_STORAGE.lock().unwrap();
-    storage.push(new_link);
+pub async fn insert_link(d1: &D1Database, input: &DailyLinkInput) -> Result<Option<DailyLink>> {
+    let [url, platform, reward, weight, starts_at, ends_at, enabled] = input.bind_values();
+    let link = d1
+        .prepare(format!(
+            "INSERT INTO daily_links (url, platform, reward, weight, starts_at, ends_at, enabled, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+             RETURNING {}",
+            LINK_COLUMNS
+        ))
+        .bind(&[
+            url,
+            platform,
+            reward,
+            weight,
+            starts_at,
+            ends_at,
+            enabled,
+            (Utc::now().timestamp() as f64).into(),
+        ])?
+        .first::<DailyLink>(None)
+        .await?;
+
+    invalidate_link_cache();
+    Ok(link)
 }
 
-/// Delete a link by URL (exact match)
-pub fn delete_link_by_url(url: &str) -> bool {
-    let mut storage = LINK_STORAGE.lock().unwrap();
-    let original_len = storage.len();
-    storage.retain(|link| link.url != url);
-    storage.len() != original_len
+/// Overwrite a link's settings. Returns `None` if there is no such link.
+pub async fn update_link(
+    d1: &D1Database,
+    id: i64,
+    input: &DailyLinkInput,
+) -> Result<Option<DailyLink>> {
+    let [url, platform, reward, weight, starts_at, ends_at, enabled] = input.bind_values();
+    let link = d1
+        .prepare(format!(
+            "UPDATE daily_links
+             SET url = ?, platform = ?, reward = ?, weight = ?, starts_at = ?, ends_at = ?, enabled = ?
+             WHERE id = ?
+             RETURNING {}",
+            LINK_COLUMNS
+        ))
+        .bind(&[
+            url,
+            platform,
+            reward,
+            weight,
+            starts_at,
+            ends_at,
+            enabled,
+            (id as f64).into(),
+        ])?
+        .first::<DailyLink>(None)
+        .await?;
+
+    invalidate_link_cache();
+    Ok(link)
 }
 
-/// View all links (debug/test only)
-pub fn list_all_links() -> Vec<Links> {
-    LINK_STORAGE.lock().unwrap().clone()
+pub async fn delete_link(d1: &D1Database, id: i64) -> Result<bool> {
+    let removed = d1
+        .prepare("DELETE FROM daily_links WHERE id = ?")
+        .bind(&[(id as f64).into()])?
+        .run()
+        .await?
+        .meta()?
+        .and_then(|m| m.changes)
+        .unwrap_or(0);
+
+    invalidate_link_cache();
+    Ok(removed > 0)
 }
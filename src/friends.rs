@@ -0,0 +1,209 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use worker::{D1Database, Result};
+
+use crate::JsValue;
+
+// Every accepted friend of ?1, plus ?1 itself, for use inside `IN (...)` filters
+pub const FRIEND_IDS_SUBQUERY: &str = r#"
+    SELECT addressee_id FROM friends WHERE requester_id = ?1 AND status = 'Accepted'
+    UNION
+    SELECT requester_id FROM friends WHERE addressee_id = ?1 AND status = 'Accepted'
+    UNION
+    SELECT ?1
+"#;
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
+pub enum FriendStatus {
+    Pending,
+    Accepted,
+}
+
+impl FriendStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FriendStatus::Pending => "Pending",
+            FriendStatus::Accepted => "Accepted",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize)]
+pub struct Friendship {
+    pub requester_id: String,
+    pub addressee_id: String,
+    pub status: FriendStatus,
+    pub created_at: i64,
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize)]
+pub struct FriendEntry {
+    pub user_id: String,
+    pub user_name: Option<String>,
+    pub pfp: usize,
+    pub product: usize,
+    pub status: FriendStatus,
+    pub outgoing: bool, // true when the caller sent the request
+}
+
+/// Outcome of sending a friend request
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestOutcome {
+    Sent,
+    // The other player had already asked us, so the request doubled as an accept
+    Accepted,
+    AlreadyPending,
+    AlreadyFriends,
+}
+
+/// Look up the friendship between two players regardless of who sent the request
+pub async fn get_friendship(d1: &D1Database, a: &str, b: &str) -> Result<Option<Friendship>> {
+    d1.prepare(
+        "SELECT requester_id, addressee_id, status, created_at FROM friends
+         WHERE (requester_id = ?1 AND addressee_id = ?2) OR (requester_id = ?2 AND addressee_id = ?1)",
+    )
+    .bind(&[a.into(), b.into()])?
+    .first::<Friendship>(None)
+    .await
+}
+
+pub async fn send_friend_request(d1: &D1Database, from: &str, to: &str) -> Result<RequestOutcome> {
+    match get_friendship(d1, from, to).await? {
+        Some(f) if f.status == FriendStatus::Accepted => Ok(RequestOutcome::AlreadyFriends),
+        Some(f) if f.requester_id == from => Ok(RequestOutcome::AlreadyPending),
+        Some(_) => {
+            accept_friend_request(d1, from, to).await?;
+            Ok(RequestOutcome::Accepted)
+        }
+        None => {
+            insert_friendship(d1, from, to, FriendStatus::Pending).await?;
+            Ok(RequestOutcome::Sent)
+        }
+    }
+}
+
+/// Accept a pending request `requester` sent to `addressee`. Returns false if there was none.
+pub async fn accept_friend_request(
+    d1: &D1Database,
+    addressee: &str,
+    requester: &str,
+) -> Result<bool> {
+    let pending = d1
+        .prepare(
+            "SELECT requester_id, addressee_id, status, created_at FROM friends
+             WHERE requester_id = ? AND addressee_id = ? AND status = 'Pending'",
+        )
+        .bind(&[requester.into(), addressee.into()])?
+        .first::<Friendship>(None)
+        .await?;
+
+    if pending.is_none() {
+        return Ok(false);
+    }
+
+    d1.prepare(
+        "UPDATE friends SET status = 'Accepted' WHERE requester_id = ? AND addressee_id = ?",
+    )
+    .bind(&[requester.into(), addressee.into()])?
+    .run()
+    .await?;
+
+    Ok(true)
+}
+
+/// Remove a friendship or cancel/decline a pending request, in either direction
+pub async fn remove_friendship(d1: &D1Database, a: &str, b: &str) -> Result<bool> {
+    let existed = get_friendship(d1, a, b).await?.is_some();
+
+    d1.prepare(
+        "DELETE FROM friends
+         WHERE (requester_id = ?1 AND addressee_id = ?2) OR (requester_id = ?2 AND addressee_id = ?1)",
+    )
+    .bind(&[a.into(), b.into()])?
+    .run()
+    .await?;
+
+    Ok(existed)
+}
+
+/// Make two players friends straight away (used for referrals)
+pub async fn add_accepted_friendship(d1: &D1Database, a: &str, b: &str) -> Result<()> {
+    if let Some(f) = get_friendship(d1, a, b).await? {
+        if f.status == FriendStatus::Accepted {
+            return Ok(());
+        }
+        remove_friendship(d1, a, b).await?;
+    }
+    insert_friendship(d1, a, b, FriendStatus::Accepted).await
+}
+
+async fn insert_friendship(
+    d1: &D1Database,
+    requester: &str,
+    addressee: &str,
+    status: FriendStatus,
+) -> Result<()> {
+    d1.prepare(
+        "INSERT INTO friends (requester_id, addressee_id, status, created_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(&[
+        requester.into(),
+        addressee.into(),
+        status.as_str().into(),
+        JsValue::from(Utc::now().timestamp() as f64),
+    ])?
+    .run()
+    .await?;
+
+    Ok(())
+}
+
+/// Friends and pending requests (both directions) of a player
+pub async fn list_friends(d1: &D1Database, user_id: &str) -> Result<Vec<FriendEntry>> {
+    d1.prepare(
+        r#"
+        SELECT
+            user_profile.user_id as "user_id",
+            user_profile.user_name as "user_name",
+            COALESCE(user_profile.pfp, 0) as "pfp",
+            COALESCE(progress.product, 0) as "product",
+            friends.status as "status",
+            CASE WHEN friends.requester_id = ?1 THEN 1 ELSE 0 END as "outgoing"
+        FROM friends
+        JOIN user_profile ON user_profile.user_id =
+            CASE WHEN friends.requester_id = ?1 THEN friends.addressee_id ELSE friends.requester_id END
+        LEFT JOIN progress ON progress.user_id = user_profile.user_id
+        WHERE friends.requester_id = ?1 OR friends.addressee_id = ?1
+        ORDER BY friends.status, progress.product DESC
+        "#,
+    )
+    .bind(&[user_id.into()])?
+    .all()
+    .await?
+    .results::<FriendEntryRow>()
+    .map(|rows| rows.into_iter().map(FriendEntry::from).collect())
+}
+
+// SQLite hands booleans back as integers
+#[derive(Deserialize)]
+struct FriendEntryRow {
+    user_id: String,
+    user_name: Option<String>,
+    pfp: usize,
+    product: usize,
+    status: FriendStatus,
+    outgoing: u8,
+}
+
+impl From<FriendEntryRow> for FriendEntry {
+    fn from(row: FriendEntryRow) -> Self {
+        FriendEntry {
+            user_id: row.user_id,
+            user_name: row.user_name,
+            pfp: row.pfp,
+            product: row.product,
+            status: row.status,
+            outgoing: row.outgoing != 0,
+        }
+    }
+}
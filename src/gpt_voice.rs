@@ -23,15 +23,13 @@ struct OpenAIErrorDetails {
     code: Option<String>,
 }
 
-pub async fn handle_transcription(mut req: Request, env: Env) -> Result<Response> {
-    console_log!("Handling transcription request");
-
+pub async fn handle_transcription(mut req: Request, env: Env, rid: &str) -> Result<Response> {
     let api_key = env.secret("OPENAI_API_KEY")?;
 
     let form_data = match req.form_data().await {
         Ok(data) => data,
         Err(e) => {
-            console_log!("Failed to parse FormData: {:?}", e);
+            log_warn!(rid, "transcribe.bad_form", "error" => e.to_string());
             return Response::error(format!("Failed to parse FormData: {}", e), 400);
         }
     };
@@ -46,7 +44,7 @@ pub async fn handle_transcription(mut req: Request, env: Env) -> Result<Response
         _ => return Response::error("'file' field is not a file", 400),
     };
 
-    console_log!("Received file: {}, size: {}", file.name(), file.size());
+    log_debug!(rid, "transcribe.file", "size" => file.size());
     let file_bytes = file.bytes().await?;
     let file_part = multipart::Part::bytes(file_bytes)
         .file_name(file.name())
@@ -69,7 +67,7 @@ pub async fn handle_transcription(mut req: Request, env: Env) -> Result<Response
     {
         Ok(res) => res,
         Err(e) => {
-            console_error!("OpenAI API request failed: {:?}", e);
+            log_error!(rid, "transcribe.request_failed", "error" => e.to_string());
             return Response::error(format!("OpenAI API request failed: {}", e), 500);
         }
     };
@@ -79,7 +77,7 @@ pub async fn handle_transcription(mut req: Request, env: Env) -> Result<Response
         let error_body: OpenAIErrorResponse = match openai_response.json().await {
             Ok(body) => body,
             Err(e) => {
-                console_error!("Failed to parse OpenAI error response: {:?}", e);
+                log_error!(rid, "transcribe.bad_error_body", "status" => status.as_u16(), "error" => e.to_string());
                 return Response::error(
                     format!(
                         "OpenAI API error ({}) and failed to parse error details",
@@ -89,7 +87,7 @@ pub async fn handle_transcription(mut req: Request, env: Env) -> Result<Response
                 );
             }
         };
-        console_error!("OpenAI API error: {:?}", error_body);
+        log_error!(rid, "transcribe.api_error", "status" => status.as_u16(), "error" => error_body.error.message);
         return Response::error(
             format!("OpenAI API Error: {}", error_body.error.message),
             status.as_u16(),
@@ -99,7 +97,7 @@ pub async fn handle_transcription(mut req: Request, env: Env) -> Result<Response
     match openai_response.json::<TranscriptionResponse>().await {
         Ok(transcription) => Response::ok(transcription.text),
         Err(e) => {
-            console_error!("Failed to parse OpenAI success response: {:?}", e);
+            log_error!(rid, "transcribe.bad_response", "error" => e.to_string());
             Response::error(format!("Failed to parse OpenAI API response: {}", e), 500)
         }
     }
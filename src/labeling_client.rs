@@ -0,0 +1,382 @@
+// Client for the external labeling backend that hands out video datapoints and
+// receives player labels. Configured from wrangler.toml:
+//
+//     LABELING_MODE        "live" (default) or "mock" (serves fixtures/labeling, no network)
+//     LABELING_API_URL     base URL, e.g. https://labeling.example.com
+//     LABELING_API_KEY     secret, sent as a bearer token when set
+//     LABELING_TIMEOUT_MS  per attempt, default 5000
+//     LABELING_MAX_RETRIES retries after the first attempt, default 2
+//     LABELING_WEBHOOK_SECRET secret the backend signs /api/notify_task_result calls with
+
+use futures::future::{select, Either};
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use worker::{AbortController, Delay, Env, Fetch, Headers, Method, Request, RequestInit};
+
+use crate::skill::DEFAULT_DIFFICULTY;
+use crate::types::{LabelSubmission, PreLabel, VideoTask};
+
+const GET_VIDEOS_PATH: &str = "/api/get-videos";
+const LABEL_DATAPOINT_PATH: &str = "/api/game/label-datapoint";
+
+// Hex HMAC-SHA256 of the raw request body under LABELING_WEBHOOK_SECRET
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "X-Labeling-Signature";
+
+const DEFAULT_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_MAX_RETRIES: u32 = 2;
+// Doubled after every failed attempt
+const RETRY_BACKOFF_MS: u64 = 200;
+
+const VIDEO_TASK_FIXTURES: &str = include_str!("../fixtures/labeling/video_tasks.json");
+
+#[derive(Debug)]
+pub enum LabelingError {
+    NotConfigured(&'static str),
+    Timeout,
+    Transport(String),
+    // Non-2xx answer from the backend
+    Status(u16),
+    Decode(String),
+}
+
+impl fmt::Display for LabelingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LabelingError::NotConfigured(what) => {
+                write!(f, "labeling backend not configured: {}", what)
+            }
+            LabelingError::Timeout => write!(f, "labeling backend timed out"),
+            LabelingError::Transport(e) => write!(f, "labeling backend unreachable: {}", e),
+            LabelingError::Status(status) => write!(f, "labeling backend answered {}", status),
+            LabelingError::Decode(e) => write!(f, "unexpected labeling backend response: {}", e),
+        }
+    }
+}
+
+impl LabelingError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            LabelingError::Timeout | LabelingError::Transport(_) => true,
+            LabelingError::Status(status) => *status == 429 || *status >= 500,
+            LabelingError::NotConfigured(_) | LabelingError::Decode(_) => false,
+        }
+    }
+}
+
+impl From<LabelingError> for worker::Error {
+    fn from(e: LabelingError) -> Self {
+        worker::Error::RustError(e.to_string())
+    }
+}
+
+pub type LabelingResult<T> = std::result::Result<T, LabelingError>;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GetVideosRequest {
+    pub number_of_datapoints: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct RemotePreLabel {
+    pub map_position: String,
+    pub summary: String,
+    pub keywords: Vec<String>,
+}
+
+/// A datapoint as the backend sends it
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RemoteVideoTask {
+    #[serde(rename = "_id")]
+    pub id: String,
+    #[serde(default)]
+    pub task_id: String,
+    #[serde(rename = "mediaUrl", default)]
+    pub media_url: String,
+    #[serde(rename = "preLabel", default)]
+    pub pre_label: RemotePreLabel,
+    // Elo-style rating, unrated datapoints start at DEFAULT_DIFFICULTY
+    #[serde(default)]
+    pub difficulty: Option<f64>,
+}
+
+impl From<RemoteVideoTask> for VideoTask {
+    fn from(task: RemoteVideoTask) -> Self {
+        VideoTask {
+            id: task.id,
+            task_id: task.task_id,
+            media_url: task.media_url,
+            pre_label: PreLabel {
+                map_position: task.pre_label.map_position,
+                summary: task.pre_label.summary,
+                keywords: task.pre_label.keywords,
+            },
+            visited: false,
+            difficulty: task
+                .difficulty
+                .filter(|d| d.is_finite())
+                .unwrap_or(DEFAULT_DIFFICULTY),
+        }
+    }
+}
+
+/// A player's label: what they submitted plus the prelabel it amounts to
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelRequest {
+    pub datapoint_id: String,
+    pub label: LabelSubmission,
+    pub corrected_label: PreLabel,
+}
+
+pub trait LabelingBackend {
+    fn fetch_video_tasks(
+        &self,
+        count: usize,
+    ) -> impl Future<Output = LabelingResult<Vec<VideoTask>>>;
+
+    /// `idempotency_key` lets the backend drop a retried submission it already has
+    fn submit_label(
+        &self,
+        request: &LabelRequest,
+        idempotency_key: &str,
+    ) -> impl Future<Output = LabelingResult<()>>;
+}
+
+pub struct HttpLabelingBackend {
+    base_url: Option<String>,
+    api_key: Option<String>,
+    timeout: Duration,
+    max_retries: u32,
+}
+
+impl HttpLabelingBackend {
+    pub fn from_env(env: &Env) -> Self {
+        let var = |name: &str| env.var(name).ok().map(|v| v.to_string());
+        Self {
+            base_url: var("LABELING_API_URL").map(|url| url.trim_end_matches('/').to_string()),
+            api_key: env.secret("LABELING_API_KEY").ok().map(|s| s.to_string()),
+            timeout: Duration::from_millis(
+                var("LABELING_TIMEOUT_MS")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_TIMEOUT_MS),
+            ),
+            max_retries: var("LABELING_MAX_RETRIES")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_RETRIES),
+        }
+    }
+
+    fn build_request(
+        &self,
+        url: &str,
+        body: &str,
+        idempotency_key: Option<&str>,
+    ) -> LabelingResult<Request> {
+        let transport = |e: worker::Error| LabelingError::Transport(e.to_string());
+
+        let mut headers = Headers::new();
+        headers
+            .set("Content-Type", "application/json")
+            .map_err(transport)?;
+        if let Some(key) = &self.api_key {
+            headers
+                .set("Authorization", &format!("Bearer {}", key))
+                .map_err(transport)?;
+        }
+        if let Some(key) = idempotency_key {
+            headers.set("Idempotency-Key", key).map_err(transport)?;
+        }
+
+        Request::new_with_init(
+            url,
+            &RequestInit {
+                method: Method::Post,
+                body: Some(body.into()),
+                headers,
+                ..Default::default()
+            },
+        )
+        .map_err(transport)
+    }
+
+    async fn attempt(&self, request: Request) -> LabelingResult<worker::Response> {
+        let controller = AbortController::default();
+        let signal = controller.signal();
+        let fetch =
+            Box::pin(async move { Fetch::Request(request).send_with_signal(&signal).await });
+        let timeout = Box::pin(Delay::from(self.timeout));
+
+        match select(fetch, timeout).await {
+            Either::Left((Ok(response), _)) => match response.status_code() {
+                200..=299 => Ok(response),
+                status => Err(LabelingError::Status(status)),
+            },
+            Either::Left((Err(e), _)) => Err(LabelingError::Transport(e.to_string())),
+            Either::Right(_) => {
+                controller.abort();
+                Err(LabelingError::Timeout)
+            }
+        }
+    }
+
+    /// POST `body` to `path`, retrying timeouts, network errors, 429 and 5xx
+    async fn post_json<B: Serialize, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+        idempotency_key: Option<&str>,
+    ) -> LabelingResult<Option<T>> {
+        let base_url = self
+            .base_url
+            .as_deref()
+            .ok_or(LabelingError::NotConfigured("LABELING_API_URL"))?;
+        let url = format!("{}{}", base_url, path);
+        let body = serde_json::to_string(body).map_err(|e| LabelingError::Decode(e.to_string()))?;
+
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .attempt(self.build_request(&url, &body, idempotency_key)?)
+                .await;
+
+            match result {
+                Ok(mut response) => {
+                    let text = response
+                        .text()
+                        .await
+                        .map_err(|e| LabelingError::Transport(e.to_string()))?;
+                    if text.trim().is_empty() {
+                        return Ok(None);
+                    }
+                    return serde_json::from_str(&text)
+                        .map(Some)
+                        .map_err(|e| LabelingError::Decode(e.to_string()));
+                }
+                Err(e) if e.is_retryable() && attempt < self.max_retries => {
+                    Delay::from(Duration::from_millis(RETRY_BACKOFF_MS << attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl LabelingBackend for HttpLabelingBackend {
+    async fn fetch_video_tasks(&self, count: usize) -> LabelingResult<Vec<VideoTask>> {
+        let tasks: Option<Vec<RemoteVideoTask>> = self
+            .post_json(
+                GET_VIDEOS_PATH,
+                &GetVideosRequest {
+                    number_of_datapoints: count,
+                },
+                None,
+            )
+            .await?;
+        Ok(tasks
+            .unwrap_or_default()
+            .into_iter()
+            .map(VideoTask::from)
+            .collect())
+    }
+
+    async fn submit_label(
+        &self,
+        request: &LabelRequest,
+        idempotency_key: &str,
+    ) -> LabelingResult<()> {
+        self.post_json::<_, serde_json::Value>(LABEL_DATAPOINT_PATH, request, Some(idempotency_key))
+            .await
+            .map(|_| ())
+    }
+}
+
+/// Serves the fixtures in fixtures/labeling and accepts labels for them, so
+/// task generation and labeling work without the real backend
+pub struct MockLabelingBackend;
+
+impl MockLabelingBackend {
+    pub fn fixtures() -> Vec<RemoteVideoTask> {
+        serde_json::from_str(VIDEO_TASK_FIXTURES).unwrap_or_default()
+    }
+}
+
+impl LabelingBackend for MockLabelingBackend {
+    async fn fetch_video_tasks(&self, count: usize) -> LabelingResult<Vec<VideoTask>> {
+        Ok(Self::fixtures()
+            .into_iter()
+            .take(count)
+            .map(VideoTask::from)
+            .collect())
+    }
+
+    async fn submit_label(
+        &self,
+        request: &LabelRequest,
+        _idempotency_key: &str,
+    ) -> LabelingResult<()> {
+        if !Self::fixtures()
+            .iter()
+            .any(|task| task.id == request.datapoint_id)
+        {
+            return Err(LabelingError::Status(404));
+        }
+        Ok(())
+    }
+}
+
+pub enum LabelingClient {
+    Http(HttpLabelingBackend),
+    Mock(MockLabelingBackend),
+}
+
+impl LabelingClient {
+    pub fn from_env(env: &Env) -> Self {
+        match env.var("LABELING_MODE").map(|v| v.to_string()).as_deref() {
+            Ok("mock") => LabelingClient::Mock(MockLabelingBackend),
+            _ => LabelingClient::Http(HttpLabelingBackend::from_env(env)),
+        }
+    }
+}
+
+impl LabelingBackend for LabelingClient {
+    async fn fetch_video_tasks(&self, count: usize) -> LabelingResult<Vec<VideoTask>> {
+        match self {
+            LabelingClient::Http(backend) => backend.fetch_video_tasks(count).await,
+            LabelingClient::Mock(backend) => backend.fetch_video_tasks(count).await,
+        }
+    }
+
+    async fn submit_label(
+        &self,
+        request: &LabelRequest,
+        idempotency_key: &str,
+    ) -> LabelingResult<()> {
+        match self {
+            LabelingClient::Http(backend) => backend.submit_label(request, idempotency_key).await,
+            LabelingClient::Mock(backend) => backend.submit_label(request, idempotency_key).await,
+        }
+    }
+}
+
+/// Whether `signature` is the labeling backend's signature of `body`. Always
+/// false while LABELING_WEBHOOK_SECRET is unset, so the webhook fails closed.
+pub fn verify_webhook_signature(env: &Env, body: &[u8], signature: &str) -> bool {
+    let Ok(secret) = env.secret("LABELING_WEBHOOK_SECRET") else {
+        return false;
+    };
+    let Ok(signature) = hex::decode(signature.trim()) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.to_string().as_bytes()) else {
+        return false;
+    };
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
@@ -2,6 +2,8 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use worker::{D1Database, Env, Request, Response, Result};
 
+use crate::friends::FRIEND_IDS_SUBQUERY;
+
 #[derive(Serialize, Deserialize)]
 pub struct LeaderboardEntry {
     pub user_id: String,
@@ -19,6 +21,7 @@ struct RangeQuery {
     p_min: Option<usize>,
     p_max: Option<usize>,
     user_id: Option<String>, // 🆕 for rank lookup
+    friends_only: Option<bool>, // rank only user_id and their friends
 }
 
 // Main handler for POST /api/leaderboard
@@ -29,6 +32,21 @@ pub async fn handle_leaderboard(mut req: Request, env: &Env) -> Result<Response>
     let p_min = body.as_ref().and_then(|b| b.p_min).unwrap_or(0);
     let p_max = body.as_ref().and_then(|b| b.p_max).unwrap_or(usize::MAX);
     let user_id_opt = body.as_ref().and_then(|b| b.user_id.clone());
+    let friends_only = body.as_ref().and_then(|b| b.friends_only).unwrap_or(false);
+
+    if friends_only {
+        let Some(user_id) = user_id_opt else {
+            return Response::error("friends_only requires user_id", 400);
+        };
+
+        let entries = get_friends_in_range(&d1, p_min, p_max, &user_id).await?;
+        let user_rank = get_user_friend_rank(&d1, p_min, p_max, &user_id).await?;
+
+        return Response::from_json(&json!({
+            "entries": entries,
+            "user_rank": user_rank
+        }));
+    }
 
     let entries = get_players_in_range(&d1, p_min, p_max).await?;
 
@@ -106,3 +124,72 @@ async fn get_user_rank(
 
     Ok(rank)
 }
+
+async fn get_friends_in_range(
+    d1: &D1Database,
+    p_min: usize,
+    p_max: usize,
+    user_id: &str,
+) -> Result<Vec<LeaderboardEntry>> {
+    let query = format!(
+        r#"
+        SELECT
+            user_profile.user_id as "user_id",
+            COALESCE(user_profile.user_name, '') as "user_name",
+            COALESCE(user_profile.pfp, 0) as "pfp",
+            progress.product as "product",
+            COALESCE(progress.social_score, 0) as "social_score",
+            COALESCE(progress.iq, 0) as "iq",
+            COALESCE(game_state.king_lvl, 0) as "king_lvl",
+            COALESCE(user_data.league, 'bronze') as "league"
+        FROM user_profile
+        JOIN progress ON user_profile.user_id = progress.user_id
+        JOIN game_state ON user_profile.user_id = game_state.user_id
+        JOIN user_data ON user_profile.user_id = user_data.user_id
+        WHERE progress.product BETWEEN ?2 AND ?3
+        AND user_profile.user_id IN ({FRIEND_IDS_SUBQUERY})
+        ORDER BY progress.product DESC
+        LIMIT 100
+        "#
+    );
+
+    let entries = d1
+        .prepare(query)
+        .bind(&[user_id.into(), p_min.into(), p_max.into()])?
+        .all()
+        .await?
+        .results::<LeaderboardEntry>()?;
+
+    Ok(entries)
+}
+
+// Same dense ranking as get_user_rank, restricted to the user's friends
+async fn get_user_friend_rank(
+    d1: &D1Database,
+    p_min: usize,
+    p_max: usize,
+    user_id: &str,
+) -> Result<usize> {
+    let query = format!(
+        r#"
+        SELECT COUNT(DISTINCT product) + 1 as rank
+        FROM progress
+        WHERE product BETWEEN ?2 AND ?3
+        AND product >= COALESCE(
+            (SELECT product FROM progress WHERE user_id = ?1),
+            -1
+        )
+        AND user_id != ?1
+        AND user_id IN ({FRIEND_IDS_SUBQUERY})
+        "#
+    );
+
+    let rank: usize = d1
+        .prepare(query)
+        .bind(&[user_id.into(), p_min.into(), p_max.into()])?
+        .first::<usize>(Some("rank"))
+        .await?
+        .ok_or_else(|| worker::Error::RustError("User not found".to_string()))?;
+
+    Ok(rank)
+}
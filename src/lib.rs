@@ -1,42 +1,100 @@
 use futures::TryStreamExt;
+use rate_limit::TokenBucket;
 use serde::{Deserialize, Serialize};
 use sha2::{digest::Update, Digest};
 use sql::UserCredentials;
+use std::collections::HashMap;
 use types::{DurableObjectAugmentedMsg, Op, UserData, WsMsg};
 use utils::is_registered;
 use wasm_bindgen::JsValue;
 use worker::*;
 
+// Declared first so the log_* macros are visible in every other module
+#[macro_use]
+mod logging;
+
+mod account;
+mod admin;
+mod consensus;
 mod daily_task;
+mod friends;
 mod gpt_voice;
+mod labeling_client;
 mod leaderboard;
+mod link_tracking;
+mod login_guard;
+mod mailer;
 mod notification;
 mod op_resolver;
+mod quests;
+mod rate_limit;
+mod referral;
 mod registry;
+mod reward_track;
+mod skill;
 mod sql;
+mod streak;
 mod types;
 mod utils;
+mod validation;
+mod video_pool;
 
 #[durable_object]
 struct UserDataWrapper {
     state: State,
     env: Env,
+    // Per-op token buckets; in memory only, so they reset if the DO is evicted
+    op_buckets: HashMap<String, TokenBucket>,
 }
 
 #[durable_object]
 impl DurableObject for UserDataWrapper {
     fn new(state: State, env: Env) -> Self {
-        Self { state, env }
+        Self {
+            state,
+            env,
+            op_buckets: HashMap::new(),
+        }
     }
 
     async fn fetch(&mut self, mut req: Request) -> Result<Response> {
-        let op_request: DurableObjectAugmentedMsg = match req.json().await {
+        logging::init(&self.env);
+        let mut op_request: DurableObjectAugmentedMsg = match req.json().await {
             Ok(op) => op,
             Err(e) => {
-                console_log!("Failed to parse OpRequest: {:?}", e);
+                log_warn!("", "do.bad_request", "error" => e.to_string());
                 return Response::error("Invalid request format", 400);
             }
         };
+        if op_request.request_id.is_empty() {
+            op_request.request_id = logging::new_request_id();
+        }
+        let rid = op_request.request_id.clone();
+        log_debug!(&rid, "do.op", "user_id" => op_request.user_id, "op" => op_request.op.kind());
+
+        if let Err(e) = sql::ensure_schema(&self.env.d1("D1_DATABASE")?).await {
+            log_error!(&rid, "do.schema_failed", "error" => e.to_string());
+            return Response::error("Internal Server Error", 500);
+        }
+
+        if let Some(limited) = rate_limit::check_op(&self.env, &mut self.op_buckets, &op_request.op)
+        {
+            return limited;
+        }
+
+        // Login turns banned players away; this also stops sockets opened before the ban
+        if !op_request.op.ignores_ban() {
+            match admin::active_ban(&self.env.d1("D1_DATABASE")?, &op_request.user_id).await {
+                Ok(Some(ban)) => {
+                    log_info!(&rid, "do.banned", "user_id" => op_request.user_id);
+                    return admin::banned_response(&ban);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log_error!(&rid, "do.ban_lookup_failed", "user_id" => op_request.user_id, "error" => e.to_string())
+                }
+            }
+        }
 
         let mut user_data: UserData =
             self.state
@@ -54,12 +112,11 @@ impl DurableObject for UserDataWrapper {
 
                     user
                 });
-        console_log!("Host: {:?}", req.url());
 
         if !is_registered(&self.env.d1("D1_DATABASE").unwrap(), &op_request.user_id).await
             && !matches!(op_request.op, Op::Register(_))
         {
-            console_log!("{}",op_request.user_id);
+            log_info!(&rid, "do.not_registered", "user_id" => op_request.user_id);
             return Response::error("User not registered", 400);
         } else if is_registered(&self.env.d1("D1_DATABASE").unwrap(), &op_request.user_id).await
             && matches!(op_request.op, Op::Register(_))
@@ -73,9 +130,28 @@ impl DurableObject for UserDataWrapper {
             .resolve_op(&op_request, &self.env.d1("D1_DATABASE").unwrap(), &self.env)
             .await?;
 
+        if matches!(op_request.op, Op::DeleteAccount(_)) && response.status_code() == 200 {
+            // D1 rows are already gone, drop everything the DO holds too
+            if let Err(e) = self.state.storage().delete_all().await {
+                log_error!(&rid, "do.storage_delete_failed", "user_id" => op_request.user_id, "error" => e.to_string());
+                return Response::error("Internal Server Error", 500);
+            }
+            return Ok(response);
+        }
+
+        if user_data.social.pending_referrer.is_some() {
+            referral::credit_pending_referral(
+                &mut user_data,
+                &self.env.d1("D1_DATABASE").unwrap(),
+                &self.env,
+                &rid,
+            )
+            .await;
+        }
+
         // if !matches!(op_request.op, Op::GetData) {
             if let Err(e) = self.state.storage().put("user_data", &user_data).await {
-                console_log!("Storage put error: {:?}", e);
+                log_error!(&rid, "do.storage_put_failed", "user_id" => op_request.user_id, "error" => e.to_string());
                 return Response::error("Internal Server Error", 500);
             }
         // }
@@ -92,78 +168,178 @@ struct RegisterBody {
 
 #[event(fetch)]
 pub async fn fetch(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
-    console_log!("Fetching");
+    logging::init(&env);
+    let rid = logging::request_id(&req);
     let url = req.url()?;
     let path = url.path();
 
-    console_log!("Path: {:?}", path);
+    log_debug!(&rid, "http.request", "method" => format!("{:?}", req.method()), "path" => path);
     if path == "/api/leaderboard" {
-        console_log!("Matched leaderboard route");
         if req.method() != Method::Get {
             return Response::error("Method Not Allowed", 405);
         }
-        let result = leaderboard::handle_leaderboard(req, &env).await;
-        console_log!("Leaderboard handler result: {:?}", result);
-        return result;
+        return leaderboard::handle_leaderboard(req, &env).await;
+    } else if path == "/api/verify_email" {
+        if req.method() != Method::Get {
+            return Response::error("Method Not Allowed", 405);
+        }
+        return account::handle_verify_email(req, &env).await;
+    } else if path == "/api/password_reset/request" {
+        if req.method() != Method::Post {
+            return Response::error("Method Not Allowed", 405);
+        }
+        if let Some(limited) =
+            rate_limit::check_route(&env, "password_reset", &rate_limit::client_ip(&req), &rid)
+                .await
+        {
+            return limited;
+        }
+        return account::handle_password_reset_request(req, &env).await;
+    } else if path == "/api/password_reset/confirm" {
+        if req.method() != Method::Post {
+            return Response::error("Method Not Allowed", 405);
+        }
+        if let Some(limited) =
+            rate_limit::check_route(&env, "password_reset", &rate_limit::client_ip(&req), &rid)
+                .await
+        {
+            return limited;
+        }
+        return account::handle_password_reset_confirm(req, &env).await;
+    } else if path.starts_with("/r/") {
+        if req.method() != Method::Get {
+            return Response::error("Method Not Allowed", 405);
+        }
+        return link_tracking::handle_redirect(req, &env, &rid).await;
+    } else if path.starts_with("/api/admin/") {
+        return admin::handle_admin(req, &env, &rid).await;
     } else if path == "/api/register" {
         if req.method() != Method::Post {
             return Response::error("Method Not Allowed", 405);
         }
+        if let Some(limited) =
+            rate_limit::check_route(&env, "register", &rate_limit::client_ip(&req), &rid).await
+        {
+            return limited;
+        }
         let RegisterBody { user_id, password } = req.json().await?;
         let op = Op::Register(password);
 
-        return forward_op_to_do(&env, &DurableObjectAugmentedMsg { user_id, op }).await;
+        return forward_op_to_do(
+            &env,
+            &DurableObjectAugmentedMsg {
+                user_id,
+                op,
+                request_id: rid,
+            },
+        )
+        .await;
     } else if path == "/api/notify_task_result" {
         if req.method() != Method::Post {
             return Response::error("Method Not Allowed", 405);
         }
 
-        let input: notification::TaskResultInput = match req.json().await {
+        // Decides datapoints and pays out rewards, so only the labeling backend may call it
+        let body = req.text().await?;
+        let signature = req
+            .headers()
+            .get(labeling_client::WEBHOOK_SIGNATURE_HEADER)?
+            .unwrap_or_default();
+        if !labeling_client::verify_webhook_signature(&env, body.as_bytes(), &signature) {
+            log_warn!(&rid, "task_result.unauthorized", "ip" => rate_limit::client_ip(&req));
+            return Response::error("Unauthorized", 401);
+        }
+
+        let input: notification::TaskResultInput = match serde_json::from_str(&body) {
             Ok(data) => data,
             Err(_) => return Response::error("Invalid JSON", 400),
         };
 
-        return match notification::notify_task_result(input, &env).await {
-            Ok(_) => Response::ok("Notifications sent"),
-            Err(e) => Response::error(format!("Failed: {}", e), 500),
-        };
+        // Overrides a datapoint the worker has not scored yet. A retried override
+        // redelivers the stored result; players already paid are skipped.
+        let db = env.d1("D1_DATABASE")?;
+        let result =
+            if consensus::claim_result(&db, &input, consensus::ResultSource::Override).await? {
+                input
+            } else {
+                match consensus::stored_result(&db, &input.datapoint_id).await? {
+                    Some((consensus::ResultSource::Override, stored)) => stored,
+                    _ => return Response::error("Datapoint already resolved", 409),
+                }
+            };
+
+        let mut result = result;
+        if result.difficulty.is_none() {
+            result.difficulty = video_pool::difficulty(&db, &result.datapoint_id)
+                .await
+                .unwrap_or(None);
+        }
+
+        let report = notification::notify_task_result(&result, &env).await;
+        if !report.is_complete() {
+            log_warn!(&rid, "task_result.partial_delivery", "datapoint_id" => report.datapoint_id, "failed" => report.failed.len(), "delivered" => report.delivered.len());
+            if let Err(e) =
+                consensus::queue_failed_deliveries(&db, &report.datapoint_id, &report.failed).await
+            {
+                log_error!(&rid, "task_result.queue_failed", "error" => e.to_string());
+            }
+            return Ok(Response::from_json(&report)?.with_status(500));
+        }
+        return Response::from_json(&report);
     } else if path == "/api/transcribe" {
-        console_log!("Matched transcribe route");
         if req.method() != Method::Post {
             return Response::error("Method Not Allowed", 405);
         }
-        return gpt_voice::handle_transcription(req, env).await;
+        if let Some(limited) =
+            rate_limit::check_route(&env, "transcribe", &rate_limit::client_ip(&req), &rid).await
+        {
+            return limited;
+        }
+        return gpt_voice::handle_transcription(req, env, &rid).await;
     }
 
-    console_log!("Not a leaderboard or register");
-
     if let Some(upgrade_header) = req.headers().get("Upgrade")? {
         let Some( mut username_header) = req.headers().get("username")? else {
             // If username header is missing, we can immediately return Unauthorized.
-            console_log!("Unauthorized: Missing username");
             return Response::error("Unauthorized: Missing username", 401);
         };
         let Some(password_header) = req.headers().get("password")? else {
-            console_log!("Unauthorized: Missing password");
             // If password header is missing, we can immediately return Unauthorized.
             return Response::error("Unauthorized: Missing password", 401);
         };
 
-        console_log!(
-            "Username header: {:?}, Password header: {:?}",
-            username_header,
-            password_header
-        );
+        if let Some(limited) =
+            rate_limit::check_route(&env, "login", &rate_limit::client_ip(&req), &rid).await
+        {
+            return limited;
+        }
+
         // Authenticate against the database
         let db = env.d1("D1_DATABASE")?;
-        console_log!("Database");
+
+        let ip_key = login_guard::ip_key(&rate_limit::client_ip(&req));
+        match login_guard::locked_for(&db, &ip_key).await {
+            Ok(Some(secs)) => return rate_limit::rate_limited_response("login", secs as u64),
+            Ok(None) => {}
+            Err(e) => log_error!(&rid, "login.guard_lookup_failed", "error" => e.to_string()),
+        }
+
         match sql::get_user_credentials(&db, &username_header).await {
             Ok(Some(UserCredentials {
                 user_id,
                 user_name,
                 password,
             })) => {
-                
+                let account_key = login_guard::account_key(&user_id);
+                match login_guard::locked_for(&db, &account_key).await {
+                    Ok(Some(secs)) => {
+                        return rate_limit::rate_limited_response("login", secs as u64)
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        log_error!(&rid, "login.guard_lookup_failed", "error" => e.to_string())
+                    }
+                }
 
                 let sha256 = sha2::Sha256::new();
 
@@ -172,27 +348,41 @@ pub async fn fetch(mut req: Request, env: Env, _ctx: Context) -> Result<Response
                 if (user_name.as_ref().map(|s| s == &username_header).unwrap_or(false)
     || user_id == username_header)
     && password == hex::encode(password_header) {
-                    // Credentials match, proceed with WebSocket upgrade
-                    
+                    // Credentials match, proceed with WebSocket upgrade unless banned
+                    match admin::active_ban(&db, &user_id).await {
+                        Ok(Some(ban)) => {
+                            log_info!(&rid, "login.banned", "user_id" => user_id);
+                            return admin::banned_response(&ban);
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            log_error!(&rid, "login.ban_lookup_failed", "user_id" => user_id, "error" => e.to_string())
+                        }
+                    }
+                    if let Err(e) = login_guard::clear(&db, &account_key).await {
+                        log_error!(&rid, "login.guard_reset_failed", "user_id" => user_id, "error" => e.to_string());
+                    }
                 } else {
 
                     // Password doesn't match
+                    log_info!(&rid, "login.failed", "user_id" => user_id, "reason" => "invalid_credentials");
+                    record_login_failure(&env, &db, &ip_key, Some(&user_id), &rid).await;
                     return Response::error("Unauthorized: Invalid credentials", 401);
                 }
                 username_header=user_id.clone();
             }
             Ok(None) => {
                 // User not found
-                console_log!("Unauthorized: User not found");
+                log_info!(&rid, "login.failed", "reason" => "unknown_user");
+                record_login_failure(&env, &db, &ip_key, None, &rid).await;
                 return Response::error("Unauthorized: User not found", 401);
             }
             Err(e) => {
-                console_error!("Database error during authentication: {:?}", e);
+                log_error!(&rid, "login.lookup_failed", "error" => e.to_string());
                 return Response::error("Internal Server Error", 500);
             }
         }
-                console_log!("Database");
-
+        log_info!(&rid, "login.succeeded", "user_id" => username_header);
 
         if upgrade_header.to_lowercase() == "websocket" {
             let pair = WebSocketPair::new()?;
@@ -205,34 +395,35 @@ pub async fn fetch(mut req: Request, env: Env, _ctx: Context) -> Result<Response
                 let mut events = match server.events() {
                     Ok(ev) => ev,
                     Err(e) => {
-                        console_log!("Error obtaining event stream: {:?}", e);
+                        log_error!(&rid, "ws.events_failed", "error" => e.to_string());
                         return;
                     }
                 };
 
+                // Each message gets its own id, prefixed with the upgrade request's
+                let mut seq: u64 = 0;
                 while let Some(event_result) = events.try_next().await.transpose() {
                     let user_id = username_header.clone();
+                    seq += 1;
+                    let msg_rid = format!("{}-{}", rid, seq);
 
                     match event_result {
                         Ok(WebsocketEvent::Message(msg)) => {
                             let data: WsMsg = match msg.json() {
                                 Ok(d) => d,
                                 Err(e) => {
-                                    console_log!("JSON parse error: {:?}", e);
+                                    log_warn!(&msg_rid, "ws.bad_message", "user_id" => user_id, "error" => e.to_string());
                                     let _ = server.send_with_str(&format!("Error: {}", e));
                                     continue;
                                 }
                             };
 
-                            console_log!(
-                                "Received {:?} operation from {:?}",
-                                data.op,
-                                user_id.clone()
-                            );
+                            log_debug!(&msg_rid, "ws.op", "user_id" => user_id, "op" => data.op.kind());
 
-                            if matches!(data.op, Op::Register(_)) {
+                            if data.op.is_internal() {
                                 let _ = server.send_with_str(&format!(
-                                    "Error: Register operation not allowed"
+                                    "Error: {:?} operation not allowed",
+                                    data.op
                                 ));
                                 continue;
                             }
@@ -240,99 +431,47 @@ pub async fn fetch(mut req: Request, env: Env, _ctx: Context) -> Result<Response
                             match forward_op_to_do(
                                 &env_clone,
                                 &DurableObjectAugmentedMsg {
-                                    user_id,
+                                    user_id: user_id.clone(),
                                     op: data.op,
+                                    request_id: msg_rid.clone(),
                                 },
                             )
                             .await
                             {
                                 Ok(mut res) => match res.text().await {
                                     Ok(response_text) => {
-                                        console_log!(
-                                            "Sending DO response back to client: {}",
-                                            response_text
-                                        );
                                         if let Err(e) = server.send_with_str(&response_text) {
-                                            console_error!(
-                                                "Error sending WebSocket message: {}",
-                                                e
-                                            );
+                                            log_error!(&msg_rid, "ws.send_failed", "user_id" => user_id, "error" => e.to_string());
                                         }
                                     }
                                     Err(e) => {
+                                        log_error!(&msg_rid, "ws.response_unreadable", "user_id" => user_id, "error" => e.to_string());
                                         let error_msg =
                                             format!("Error reading DO response body: {}", e);
-                                        console_error!("{}", error_msg);
                                         if let Err(e) = server.send_with_str(&error_msg) {
-                                            console_error!(
-                                                "Error sending WebSocket error message: {}",
-                                                e
-                                            );
+                                            log_error!(&msg_rid, "ws.send_failed", "user_id" => user_id, "error" => e.to_string());
                                         }
                                     }
                                 },
                                 Err(e) => {
-                                    console_log!("Failed to forward operation: {:?}", e);
+                                    log_warn!(&msg_rid, "ws.op_failed", "user_id" => user_id, "error" => e.to_string());
                                     // Optionally, send an error message back to the client
                                     let error_msg = format!("Error processing operation: {}", e);
                                     if let Err(e_send) = server.send_with_str(&error_msg) {
-                                        console_error!(
-                                            "Error sending WebSocket error message: {}",
-                                            e_send
-                                        );
+                                        log_error!(&msg_rid, "ws.send_failed", "user_id" => user_id, "error" => e_send.to_string());
                                     }
                                 }
                             }
                         }
                         Ok(WebsocketEvent::Close(_)) => {
-                            console_log!("WebSocket connection closed for user: {}", user_id);
-                            match forward_op_to_do(
-                                &env_clone,
-                                &DurableObjectAugmentedMsg {
-                                    user_id: user_id.clone(),
-                                    op: Op::SyncData,
-                                },
-                            )
-                            .await
-                            {
-                                Ok(mut response) => {
-                                    match response.text().await {
-                                        Ok(text) => console_log!("SyncData on close for user {} successful: {}", user_id, text),
-                                        Err(e) => console_error!("SyncData on close for user {}: failed to read response body: {}", user_id, e),
-                                    }
-                                }
-                                Err(e) => {
-                                    console_error!("SyncData on close for user {} failed: {}", user_id, e);
-                                }
-                            }
+                            log_info!(&msg_rid, "ws.closed", "user_id" => user_id);
+                            sync_on_disconnect(&env_clone, &user_id, &msg_rid).await;
                             break;
                         }
 
                         Err(e) => {
-                            console_error!(
-                                "WebSocket event stream error for user {}: {}",
-                                user_id,
-                                e
-                            );
-                            match forward_op_to_do(
-                                &env_clone,
-                                &DurableObjectAugmentedMsg {
-                                    user_id: user_id.clone(),
-                                    op: Op::SyncData,
-                                },
-                            )
-                            .await
-                            {
-                                Ok(mut response) => {
-                                    match response.text().await {
-                                        Ok(text) => console_log!("SyncData on error for user {} successful: {}", user_id, text),
-                                        Err(e_resp) => console_error!("SyncData on error for user {}: failed to read response body: {}", user_id, e_resp),
-                                    }
-                                }
-                                Err(e_fwd) => {
-                                    console_error!("SyncData on error for user {} failed: {}", user_id, e_fwd);
-                                }
-                            }
+                            log_error!(&msg_rid, "ws.stream_error", "user_id" => user_id, "error" => e.to_string());
+                            sync_on_disconnect(&env_clone, &user_id, &msg_rid).await;
                             break;
                         }
                     }
@@ -346,60 +485,93 @@ pub async fn fetch(mut req: Request, env: Env, _ctx: Context) -> Result<Response
     Response::ok("This endpoint upgrades to WebSockets.")
 }
 
-async fn forward_op_to_do(env: &Env, data: &DurableObjectAugmentedMsg) -> Result<Response> {
-    console_log!("Starting forward_op_to_do for user: {}", data.user_id);
-
-    let do_namespace = env.durable_object("USER_DATA_WRAPPER")?;
-    console_log!("Got durable object namespace");
+/// Flush the DO's state to D1 once the socket is gone
+async fn sync_on_disconnect(env: &Env, user_id: &str, rid: &str) {
+    let result = forward_op_to_do(
+        env,
+        &DurableObjectAugmentedMsg {
+            user_id: user_id.to_string(),
+            op: Op::SyncData,
+            request_id: rid.to_string(),
+        },
+    )
+    .await;
+
+    match result {
+        Ok(_) => log_debug!(rid, "ws.sync_on_close", "user_id" => user_id),
+        Err(e) => {
+            log_error!(rid, "ws.sync_on_close_failed", "user_id" => user_id, "error" => e.to_string())
+        }
+    }
+}
 
-    let do_id = do_namespace.id_from_name(&data.user_id)?;
-    console_log!("Generated DO ID from user name");
+/// Count a failed login against the IP and (if known) the account, telling the
+/// owner when their account gets locked
+async fn record_login_failure(
+    env: &Env,
+    db: &D1Database,
+    ip_key: &str,
+    user_id: Option<&str>,
+    rid: &str,
+) {
+    if let Err(e) = login_guard::record_failure(db, ip_key, &login_guard::IP_POLICY).await {
+        log_error!(rid, "login.record_failure_failed", "error" => e.to_string());
+    }
 
-    let do_stub = do_id.get_stub()?;
-    console_log!("Got DO stub");
+    let Some(user_id) = user_id else {
+        return;
+    };
 
-    let op_request_json = match serde_json::to_string(&data) {
-        Ok(json) => {
-            console_log!("Successfully serialized op request to JSON");
-            json
-        }
-        Err(e) => {
-            console_log!("Failed to serialize op request: {:?}", e);
-            return Err(worker::Error::RustError(format!(
-                "Serialization error: {}",
-                e
-            )));
+    match login_guard::record_failure(
+        db,
+        &login_guard::account_key(user_id),
+        &login_guard::ACCOUNT_POLICY,
+    )
+    .await
+    {
+        Ok(outcome) if outcome.locked_out => {
+            log_warn!(rid, "login.locked_out", "user_id" => user_id, "locked_for_secs" => outcome.locked_for_secs);
+            let message = format!(
+                "Your account was locked for {} minutes after too many failed sign-in attempts.",
+                outcome.locked_for_secs / 60
+            );
+            if let Err(e) = notification::push_notification_to_user_do(
+                env,
+                user_id,
+                notification::NotificationType::System,
+                &message,
+                None,
+            )
+            .await
+            {
+                log_error!(rid, "login.lockout_notify_failed", "user_id" => user_id, "error" => e.to_string());
+            }
         }
-    };
+        Ok(_) => {}
+        Err(e) => log_error!(rid, "login.record_failure_failed", "user_id" => user_id, "error" => e.to_string()),
+    }
+}
+
+async fn forward_op_to_do(env: &Env, data: &DurableObjectAugmentedMsg) -> Result<Response> {
+    let do_namespace = env.durable_object("USER_DATA_WRAPPER")?;
+    let do_stub = do_namespace.id_from_name(&data.user_id)?.get_stub()?;
+
+    let op_request_json = serde_json::to_string(&data)
+        .map_err(|e| worker::Error::RustError(format!("Serialization error: {}", e)))?;
 
     let mut request_init = RequestInit::new();
     request_init.with_method(Method::Post);
     request_init.with_body(Some(JsValue::from_str(&op_request_json)));
-    console_log!("Initialized request with method and body");
 
     let request_url = "https://example.com/";
 
-    let request = match Request::new_with_init(request_url, &request_init) {
-        Ok(req) => {
-            console_log!("Successfully created request object");
-            req
-        }
-        Err(e) => {
-            console_log!("Failed to create request object: {:?}", e);
-            return Err(worker::Error::RustError(format!(
-                "Request creation error: {:?}",
-                e
-            )));
-        }
-    };
+    let request = Request::new_with_init(request_url, &request_init)
+        .map_err(|e| worker::Error::RustError(format!("Request creation error: {:?}", e)))?;
 
     let mut response = match do_stub.fetch_with_request(request).await {
-        Ok(res) => {
-            console_log!("Successfully fetched from DO");
-            res
-        }
+        Ok(res) => res,
         Err(e) => {
-            console_log!("Failed to fetch from DO: {:?}", e);
+            log_error!(&data.request_id, "do.fetch_failed", "user_id" => data.user_id, "op" => data.op.kind(), "error" => e.to_string());
             return Err(worker::Error::RustError(format!(
                 "Fetch to Durable Object failed: {:?}",
                 e
@@ -407,18 +579,22 @@ async fn forward_op_to_do(env: &Env, data: &DurableObjectAugmentedMsg) -> Result
         }
     };
 
+    // Rate-limit rejections carry a typed body meant for the client, pass them through
+    if response.status_code() == 429 {
+        return Ok(response);
+    }
+
     if response.status_code() != 200 {
         let error_message = match response.text().await {
             Ok(text) => text,
             Err(_) => "Unknown error".to_string(),
         };
-        console_log!("Received error response from DO: {}", error_message);
+        log_info!(&data.request_id, "do.op_rejected", "user_id" => data.user_id, "op" => data.op.kind(), "status" => response.status_code(), "error" => error_message);
         return Err(worker::Error::RustError(format!(
             "Durable Object Error: {}",
             error_message
         )));
     }
 
-    console_log!("Successfully completed forward_op_to_do");
     Ok(response)
 }
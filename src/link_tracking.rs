@@ -0,0 +1,165 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::future::Future;
+use worker::{Env, Request, Response, Result, Url};
+
+use crate::account::public_base_url;
+use crate::daily_task::{Links, SocialPlatform};
+use crate::forward_op_to_do;
+use crate::types::{DurableObjectAugmentedMsg, Op};
+
+// A day's links plus slack for players who open them after midnight
+pub const LINK_TOKEN_TTL_SECS: i64 = 2 * 24 * 60 * 60;
+
+/// What a `/r/{token}` link vouches for
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LinkClaims {
+    pub user_id: String,
+    pub url: String,
+    pub platform: SocialPlatform,
+    pub expires_at: i64,
+}
+
+fn signing_key(env: &Env) -> Result<Hmac<Sha256>> {
+    let secret = env.secret("LINK_SIGNING_SECRET")?.to_string();
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|e| worker::Error::RustError(format!("Invalid link signing key: {}", e)))
+}
+
+/// `<base64url(claims)>.<base64url(hmac-sha256(claims))>`
+pub fn sign_link(env: &Env, claims: &LinkClaims) -> Result<String> {
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?);
+    let mut mac = signing_key(env)?;
+    mac.update(payload.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    Ok(format!("{}.{}", payload, signature))
+}
+
+/// Claims of a genuine, unexpired token
+pub fn verify_link_token(env: &Env, token: &str) -> Option<LinkClaims> {
+    let (payload, signature) = token.split_once('.')?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+    let mut mac = signing_key(env).ok()?;
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature).ok()?;
+
+    let claims: LinkClaims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    (claims.expires_at > Utc::now().timestamp()).then_some(claims)
+}
+
+/// Tracked URL handed to the client in place of the raw campaign link
+pub fn click_url(env: &Env, user_id: &str, link: &Links) -> Result<String> {
+    let token = sign_link(
+        env,
+        &LinkClaims {
+            user_id: user_id.to_string(),
+            url: link.url.clone(),
+            platform: link.platform.clone(),
+            expires_at: Utc::now().timestamp() + LINK_TOKEN_TTL_SECS,
+        },
+    )?;
+    Ok(format!("{}/r/{}", public_base_url(env), token))
+}
+
+/// Decides whether a click-through counts. Implement this per platform (e.g.
+/// ask the platform's API whether the player now follows the account) and
+/// select it in `Verifier::from_env`.
+pub trait LinkVerifier {
+    fn verify(&self, claims: &LinkClaims) -> impl Future<Output = Result<bool>>;
+}
+
+/// Counts every signed click-through (default)
+pub struct ClickThroughVerifier;
+
+impl LinkVerifier for ClickThroughVerifier {
+    async fn verify(&self, _claims: &LinkClaims) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Rejects clicks on the listed platforms, for exercising the unverified path
+#[derive(Default)]
+pub struct MockLinkVerifier {
+    pub rejected_platforms: Vec<SocialPlatform>,
+}
+
+impl LinkVerifier for MockLinkVerifier {
+    async fn verify(&self, claims: &LinkClaims) -> Result<bool> {
+        Ok(!self.rejected_platforms.contains(&claims.platform))
+    }
+}
+
+pub enum Verifier {
+    ClickThrough(ClickThroughVerifier),
+    Mock(MockLinkVerifier),
+}
+
+impl Verifier {
+    /// LINK_VERIFIER = "click" (default) or "mock"; the mock rejects the
+    /// platforms listed in MOCK_LINK_VERIFIER_REJECT, e.g. `["Twitter"]`
+    pub fn from_env(env: &Env) -> Self {
+        match env.var("LINK_VERIFIER").map(|v| v.to_string()).as_deref() {
+            Ok("mock") => Verifier::Mock(MockLinkVerifier {
+                rejected_platforms: env
+                    .var("MOCK_LINK_VERIFIER_REJECT")
+                    .ok()
+                    .and_then(|v| serde_json::from_str(&v.to_string()).ok())
+                    .unwrap_or_default(),
+            }),
+            _ => Verifier::ClickThrough(ClickThroughVerifier),
+        }
+    }
+}
+
+impl LinkVerifier for Verifier {
+    async fn verify(&self, claims: &LinkClaims) -> Result<bool> {
+        match self {
+            Verifier::ClickThrough(verifier) => verifier.verify(claims).await,
+            Verifier::Mock(verifier) => verifier.verify(claims).await,
+        }
+    }
+}
+
+// Handler for GET /r/{token}
+pub async fn handle_redirect(req: Request, env: &Env, rid: &str) -> Result<Response> {
+    let path = req.path();
+    let token = path.trim_start_matches("/r/");
+
+    let Some(claims) = verify_link_token(env, token) else {
+        return Response::error("Invalid or expired link", 400);
+    };
+    let destination = match Url::parse(&claims.url) {
+        Ok(url) => url,
+        Err(_) => return Response::error("Invalid link", 400),
+    };
+
+    // The player still gets where they were going if recording the visit fails
+    match Verifier::from_env(env).verify(&claims).await {
+        Ok(true) => {
+            if let Err(e) = forward_op_to_do(
+                env,
+                &DurableObjectAugmentedMsg {
+                    user_id: claims.user_id.clone(),
+                    op: Op::RecordLinkVisit(claims.url.clone()),
+                    request_id: rid.to_string(),
+                },
+            )
+            .await
+            {
+                log_warn!(rid, "link.visit_not_recorded", "user_id" => claims.user_id, "url" => claims.url, "error" => e.to_string());
+            }
+        }
+        Ok(false) => {
+            log_info!(rid, "link.visit_unverified", "user_id" => claims.user_id, "platform" => claims.platform.as_str());
+        }
+        Err(e) => {
+            log_error!(rid, "link.verifier_failed", "user_id" => claims.user_id, "error" => e.to_string());
+        }
+    }
+
+    Response::redirect(destination)
+}
@@ -0,0 +1,169 @@
+// Structured logging: one JSON line per event, filtered by level and with
+// secret fields masked before anything reaches the log.
+//
+//     log_info!(&rid, "ws.upgrade", "user_id" => user_id);
+//     log_error!(&rid, "sync.failed", "user_id" => user_id, "error" => e.to_string());
+
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU8, Ordering};
+use uuid::Uuid;
+use worker::{console_error, console_log, console_warn, Env, Request};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Level> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" | "trace" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    fn from_u8(value: u8) -> Level {
+        match value {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+}
+
+// Keys whose values never get logged; matched as substrings of the lowercased key
+const SECRET_KEYS: &[&str] = &[
+    "password",
+    "token",
+    "secret",
+    "authorization",
+    "api_key",
+    "email",
+    "cookie",
+];
+
+const REDACTED: &str = "[redacted]";
+
+// Level baked in at build time (`LOG_LEVEL=debug cargo build`), info otherwise
+fn compiled_level() -> Level {
+    option_env!("LOG_LEVEL")
+        .and_then(Level::parse)
+        .unwrap_or(Level::Info)
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Apply the LOG_LEVEL var, falling back to the compiled default. Called at
+/// every entry point (fetch, DO fetch, cron).
+pub fn init(env: &Env) {
+    let level = env
+        .var("LOG_LEVEL")
+        .ok()
+        .and_then(|v| Level::parse(&v.to_string()))
+        .unwrap_or_else(compiled_level);
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn new_request_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Reuse Cloudflare's ray id so worker logs line up with the dashboard
+pub fn request_id(req: &Request) -> String {
+    req.headers()
+        .get("cf-ray")
+        .ok()
+        .flatten()
+        .unwrap_or_else(new_request_id)
+}
+
+fn is_secret_key(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    SECRET_KEYS.iter().any(|secret| key.contains(secret))
+}
+
+/// Mask every value stored under a secret-looking key, at any depth
+pub fn redact(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| {
+                    if is_secret_key(&k) && !v.is_null() {
+                        (k, Value::String(REDACTED.to_string()))
+                    } else {
+                        (k, redact(v))
+                    }
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(redact).collect()),
+        other => other,
+    }
+}
+
+pub fn emit(level: Level, request_id: &str, event: &str, fields: Value) {
+    if !enabled(level) {
+        return;
+    }
+
+    let line = json!({
+        "level": level.as_str(),
+        "event": event,
+        "request_id": request_id,
+        "fields": redact(fields),
+    })
+    .to_string();
+
+    match level {
+        Level::Error => console_error!("{}", line),
+        Level::Warn => console_warn!("{}", line),
+        Level::Info | Level::Debug => console_log!("{}", line),
+    }
+}
+
+macro_rules! log_event {
+    ($level:ident, $rid:expr, $event:expr $(, $key:literal => $value:expr)* $(,)?) => {
+        $crate::logging::emit(
+            $crate::logging::Level::$level,
+            $rid,
+            $event,
+            serde_json::json!({ $($key: $value),* }),
+        )
+    };
+}
+
+macro_rules! log_error {
+    ($($args:tt)*) => { log_event!(Error, $($args)*) };
+}
+
+macro_rules! log_warn {
+    ($($args:tt)*) => { log_event!(Warn, $($args)*) };
+}
+
+macro_rules! log_info {
+    ($($args:tt)*) => { log_event!(Info, $($args)*) };
+}
+
+macro_rules! log_debug {
+    ($($args:tt)*) => { log_event!(Debug, $($args)*) };
+}
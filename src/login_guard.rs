@@ -0,0 +1,131 @@
+use chrono::Utc;
+use serde::Deserialize;
+use worker::{D1Database, Result};
+
+use crate::JsValue;
+
+/// Back-off policy for one kind of key (account or IP)
+pub struct GuardPolicy {
+    // Failures allowed before any delay kicks in
+    pub free_attempts: u32,
+    // Delay after the first failure past free_attempts, doubled for every further one
+    pub base_delay_secs: i64,
+    pub max_delay_secs: i64,
+    // Failure count at which the key is locked out outright
+    pub lockout_threshold: u32,
+    pub lockout_secs: i64,
+}
+
+pub const ACCOUNT_POLICY: GuardPolicy = GuardPolicy {
+    free_attempts: 3,
+    base_delay_secs: 2,
+    max_delay_secs: 5 * 60,
+    lockout_threshold: 10,
+    lockout_secs: 30 * 60,
+};
+
+pub const IP_POLICY: GuardPolicy = GuardPolicy {
+    free_attempts: 10,
+    base_delay_secs: 2,
+    max_delay_secs: 10 * 60,
+    lockout_threshold: 50,
+    lockout_secs: 60 * 60,
+};
+
+// A key with no failures for this long starts from zero again
+const FAILURE_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+pub fn account_key(user_id: &str) -> String {
+    format!("user:{}", user_id)
+}
+
+pub fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+#[derive(Deserialize)]
+struct AttemptRow {
+    failures: u32,
+    locked_until: i64,
+    last_failure_at: i64,
+}
+
+pub struct FailureOutcome {
+    pub locked_for_secs: i64,
+    // True only for the failure that crossed the lockout threshold
+    pub locked_out: bool,
+}
+
+async fn get_attempts(d1: &D1Database, key: &str) -> Result<Option<AttemptRow>> {
+    d1.prepare(
+        "SELECT failures, locked_until, last_failure_at FROM login_attempts WHERE attempt_key = ?",
+    )
+    .bind(&[key.into()])?
+    .first::<AttemptRow>(None)
+    .await
+}
+
+/// Seconds until `key` may try again, or `None` if it isn't blocked
+pub async fn locked_for(d1: &D1Database, key: &str) -> Result<Option<i64>> {
+    let now = Utc::now().timestamp();
+    Ok(get_attempts(d1, key)
+        .await?
+        .map(|row| row.locked_until - now)
+        .filter(|secs| *secs > 0))
+}
+
+pub async fn record_failure(
+    d1: &D1Database,
+    key: &str,
+    policy: &GuardPolicy,
+) -> Result<FailureOutcome> {
+    let now = Utc::now().timestamp();
+
+    let previous = get_attempts(d1, key)
+        .await?
+        .filter(|row| now - row.last_failure_at < FAILURE_WINDOW_SECS)
+        .map_or(0, |row| row.failures);
+    let failures = previous + 1;
+
+    let locked_out = failures == policy.lockout_threshold;
+    let locked_for_secs = if failures >= policy.lockout_threshold {
+        policy.lockout_secs
+    } else if failures > policy.free_attempts {
+        let doublings = (failures - policy.free_attempts - 1).min(30);
+        (policy.base_delay_secs << doublings).min(policy.max_delay_secs)
+    } else {
+        0
+    };
+
+    d1.prepare(
+        "INSERT INTO login_attempts (attempt_key, failures, locked_until, last_failure_at)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(attempt_key) DO UPDATE SET
+            failures = ?2, locked_until = ?3, last_failure_at = ?4",
+    )
+    .bind(&[
+        key.into(),
+        failures.into(),
+        JsValue::from((now + locked_for_secs) as f64),
+        JsValue::from(now as f64),
+    ])?
+    .run()
+    .await?;
+
+    Ok(FailureOutcome {
+        locked_for_secs,
+        locked_out,
+    })
+}
+
+/// Forget all failures for `key` (successful login or admin unlock)
+pub async fn clear(d1: &D1Database, key: &str) -> Result<bool> {
+    let existed = get_attempts(d1, key).await?.is_some();
+
+    d1.prepare("DELETE FROM login_attempts WHERE attempt_key = ?")
+        .bind(&[key.into()])?
+        .run()
+        .await?;
+
+    Ok(existed)
+}
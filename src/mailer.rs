@@ -0,0 +1,62 @@
+use serde::Serialize;
+use std::future::Future;
+use worker::{Env, Result};
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Anything that can deliver a mail. Implement this for a real provider and
+/// select it in `Mailer::from_env`.
+pub trait MailSender {
+    fn send(&self, mail: &Mail) -> impl Future<Output = Result<()>>;
+}
+
+/// Writes mails to the worker log instead of delivering them (default)
+pub struct LogMailSender;
+
+impl MailSender for LogMailSender {
+    async fn send(&self, mail: &Mail) -> Result<()> {
+        // Body left out on purpose: it carries the verification / reset token
+        log_info!("", "mail.logged", "email" => mail.to, "subject" => mail.subject);
+        Ok(())
+    }
+}
+
+/// Logs every mail in full, token included, so the verification / reset flows
+/// can be finished locally from `wrangler dev` output. Never use it in production.
+pub struct MockMailSender;
+
+impl MailSender for MockMailSender {
+    async fn send(&self, mail: &Mail) -> Result<()> {
+        log_info!("", "mail.mock", "email" => mail.to, "subject" => mail.subject, "body" => mail.body);
+        Ok(())
+    }
+}
+
+/// The sender configured through the MAIL_SENDER var ("log" or "mock")
+pub enum Mailer {
+    Log(LogMailSender),
+    Mock(MockMailSender),
+}
+
+impl Mailer {
+    pub fn from_env(env: &Env) -> Self {
+        match env.var("MAIL_SENDER").map(|v| v.to_string()).as_deref() {
+            Ok("mock") => Mailer::Mock(MockMailSender),
+            _ => Mailer::Log(LogMailSender),
+        }
+    }
+}
+
+impl MailSender for Mailer {
+    async fn send(&self, mail: &Mail) -> Result<()> {
+        match self {
+            Mailer::Log(sender) => sender.send(mail).await,
+            Mailer::Mock(sender) => sender.send(mail).await,
+        }
+    }
+}
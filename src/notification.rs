@@ -1,3 +1,4 @@
+use crate::types::Progress;
 use crate::{Env, JsValue};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
@@ -14,6 +15,9 @@ pub struct Notification {
     pub timestamp: i64,
     pub read: Read,
     pub metadata: Option<HashMap<String, String>>,
+    // Set on reward notifications; the DO applies each key at most once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reward_key: Option<String>,
 }
 
 #[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
@@ -21,6 +25,7 @@ pub enum NotificationType {
     Referral,
     Performance,
     System,
+    Friend,
 }
 
 #[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
@@ -35,6 +40,7 @@ impl NotificationType {
             NotificationType::Referral => "Referral",
             NotificationType::Performance => "Performance",
             NotificationType::System => "System",
+            NotificationType::Friend => "Friend",
         }
     }
 }
@@ -48,27 +54,91 @@ impl Read {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub struct TaskResultInput {
     pub player_ranking: Vec<String>,      // e.g. ["addr1", "addr2", ..., "addr5"]
     pub flagged_players: Vec<String>,     // subset of player_ranking
     pub datapoint_id: String,             // for metadata
+    // Rating the players' skill is measured against; looked up in the video pool when missing
+    #[serde(default)]
+    pub difficulty: Option<f64>,
 }
 
-pub async fn push_notification_to_user_do(
-    env: &Env,
+/// Balance changes carried in a Referral / Performance notification's metadata.
+/// Deltas are signed so penalties travel the same way as rewards.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RewardPayload {
+    pub akai_balance: i64,
+    pub iq: i64,
+    pub social_score: i64,
+}
+
+impl RewardPayload {
+    /// Read the deltas from metadata; a missing or malformed key falls back to `defaults`
+    pub fn from_metadata(
+        metadata: Option<&HashMap<String, String>>,
+        defaults: RewardPayload,
+    ) -> Self {
+        let delta = |key: &str, default: i64| {
+            metadata
+                .and_then(|m| m.get(key))
+                .and_then(|v| v.trim().parse::<i64>().ok())
+                .unwrap_or(default)
+        };
+        RewardPayload {
+            akai_balance: delta("akai_balance", defaults.akai_balance),
+            iq: delta("iq", defaults.iq),
+            social_score: delta("social_score", defaults.social_score),
+        }
+    }
+
+    pub fn write_metadata(&self, metadata: &mut HashMap<String, String>) {
+        metadata.insert("akai_balance".to_string(), self.akai_balance.to_string());
+        metadata.insert("iq".to_string(), self.iq.to_string());
+        metadata.insert("social_score".to_string(), self.social_score.to_string());
+    }
+
+    /// Add the deltas to the balances, clamping at 0 and `usize::MAX`.
+    /// Returns what was actually applied.
+    pub fn apply(&self, progress: &mut Progress) -> RewardPayload {
+        RewardPayload {
+            akai_balance: apply_delta(&mut progress.akai_balance, self.akai_balance),
+            iq: apply_delta(&mut progress.iq, self.iq),
+            social_score: apply_delta(&mut progress.social_score, self.social_score),
+        }
+    }
+}
+
+fn apply_delta(balance: &mut usize, delta: i64) -> i64 {
+    let before = *balance;
+    let magnitude = usize::try_from(delta.unsigned_abs()).unwrap_or(usize::MAX);
+    *balance = if delta >= 0 {
+        before.saturating_add(magnitude)
+    } else {
+        before.saturating_sub(magnitude)
+    };
+
+    if *balance >= before {
+        i64::try_from(*balance - before).unwrap_or(i64::MAX)
+    } else {
+        i64::try_from(before - *balance).map_or(i64::MIN, |d| -d)
+    }
+}
+
+/// What one player gets for a resolved datapoint
+pub struct PerformanceOutcome {
+    pub user_id: String,
+    pub message: String,
+    pub metadata: HashMap<String, String>,
+}
+
+pub fn new_notification(
     user_id: &str,
     notification_type: NotificationType,
     message: &str,
     metadata: Option<HashMap<String, String>>,
-) -> Result<()> {
-    // 1. Get the DO namespace and stub
-    let namespace = env.durable_object("USER_DATA_WRAPPER")?;
-    let do_id = namespace.id_from_name(user_id)?;
-    let mut stub = do_id.get_stub()?;
-
-    // 2. Build a lightweight Notification and send it to the DO
-    let notification = Notification {
+) -> Notification {
+    Notification {
         notification_id: Uuid::new_v4().to_string(),
         user_id: user_id.to_string(),
         notification_type,
@@ -76,12 +146,36 @@ pub async fn push_notification_to_user_do(
         timestamp: Utc::now().timestamp(),
         read: Read::No,
         metadata,
-    };
+        reward_key: None,
+    }
+}
+
+/// Idempotency key of a player's reward for a resolved datapoint
+pub fn task_reward_key(datapoint_id: &str, user_id: &str) -> String {
+    format!("task_result:{}:{}", datapoint_id, user_id)
+}
+
+pub async fn push_notification_to_user_do(
+    env: &Env,
+    user_id: &str,
+    notification_type: NotificationType,
+    message: &str,
+    metadata: Option<HashMap<String, String>>,
+) -> Result<()> {
+    let notification = new_notification(user_id, notification_type, message, metadata);
+    send_notification(env, &notification).await?;
+    Ok(())
+}
+
+/// Hand a notification to its user's DO. Fails unless the DO accepted it.
+pub async fn send_notification(env: &Env, notification: &Notification) -> Result<serde_json::Value> {
+    let namespace = env.durable_object("USER_DATA_WRAPPER")?;
+    let stub = namespace.id_from_name(notification.user_id.as_str())?.get_stub()?;
 
     let request_body = serde_json::json!({
-        "user_id": user_id,
+        "user_id": notification.user_id,
         "op": {
-            "AddNotificationInternal": notification  // ⬅ You’ll need to add this Op variant
+            "AddNotificationInternal": notification
         }
     });
 
@@ -91,65 +185,282 @@ pub async fn push_notification_to_user_do(
 
     let req = Request::new_with_init("https://dummy-url", &init)?;
 
-    stub.fetch_with_request(req).await?;
-
-    Ok(())
+    let mut response = stub.fetch_with_request(req).await?;
+    let status = response.status_code();
+    if !(200..300).contains(&status) {
+        return Err(Error::RustError(format!(
+            "DO answered {}: {}",
+            status,
+            response.text().await.unwrap_or_default()
+        )));
+    }
+    Ok(response.json().await.unwrap_or_default())
 }
 
+/// Send a performance reward keyed by `task_reward_key`. Returns false if the
+/// player already had it, so retried deliveries never pay twice.
+pub async fn deliver_task_reward(
+    env: &Env,
+    datapoint_id: &str,
+    outcome: PerformanceOutcome,
+) -> Result<bool> {
+    let mut notification = new_notification(
+        &outcome.user_id,
+        NotificationType::Performance,
+        &outcome.message,
+        Some(outcome.metadata),
+    );
+    notification.reward_key = Some(task_reward_key(datapoint_id, &outcome.user_id));
+
+    let body = send_notification(env, &notification).await?;
+    Ok(!body["duplicate"].as_bool().unwrap_or(false))
+}
 
-pub async fn notify_task_result(input: TaskResultInput, env: &Env) -> Result<()> {
+/// Akai / IQ for each ranked player: the top five unflagged players are
+/// rewarded by rank, flagged players are penalised
+pub fn performance_outcomes(input: &TaskResultInput) -> Vec<PerformanceOutcome> {
     let unflagged_players: Vec<_> = input
         .player_ranking
         .iter()
         .filter(|user_id| !input.flagged_players.contains(user_id))
         .collect();
 
-    for user_id in &input.player_ranking {
-        let is_flagged = input.flagged_players.contains(user_id);
-
-        let (message, akai, iq) = if is_flagged {
-            ("You did the task wrong.".to_string(), -10, -15)
-        } else {
-            let rank = unflagged_players
-                .iter()
-                .position(|uid| *uid == user_id)
-                .unwrap(); // Safe since it's in player_ranking but not flagged
-
-            let (akai, iq) = match rank {
-                0 => (20, 10),
-                1 => (15, 7),
-                2 => (10, 5),
-                3 => (5, 3),
-                4 => (2, 1),
-                _ => (0, 0),
+    input
+        .player_ranking
+        .iter()
+        .map(|user_id| {
+            let is_flagged = input.flagged_players.contains(user_id);
+
+            let (message, akai_balance, iq) = if is_flagged {
+                ("You did the task wrong.".to_string(), -10, -15)
+            } else {
+                let rank = unflagged_players
+                    .iter()
+                    .position(|uid| *uid == user_id)
+                    .unwrap(); // Safe since it's in player_ranking but not flagged
+
+                let (akai, iq) = match rank {
+                    0 => (20, 10),
+                    1 => (15, 7),
+                    2 => (10, 5),
+                    3 => (5, 3),
+                    4 => (2, 1),
+                    _ => (0, 0),
+                };
+
+                (
+                    format!(
+                        "You ranked {} out of {} unflagged players.",
+                        rank + 1,
+                        unflagged_players.len()
+                    ),
+                    akai,
+                    iq,
+                )
             };
 
-            (
-                format!(
-                    "You ranked {} out of {} unflagged players.",
-                    rank + 1,
-                    unflagged_players.len()
-                ),
-                akai,
+            let mut metadata = HashMap::new();
+            RewardPayload {
+                akai_balance,
                 iq,
-            )
-        };
+                social_score: 0,
+            }
+            .write_metadata(&mut metadata);
+            metadata.insert("datapoint_id".to_string(), input.datapoint_id.clone());
+            metadata.insert(
+                "outcome".to_string(),
+                if is_flagged { "flagged" } else { "agreed" }.to_string(),
+            );
+            if let Some(difficulty) = input.difficulty {
+                metadata.insert("difficulty".to_string(), difficulty.to_string());
+            }
 
-        let mut metadata = HashMap::new();
-        metadata.insert("akai_balance".to_string(), akai.to_string());
-        metadata.insert("iq".to_string(), iq.to_string());
-        metadata.insert("datapoint_id".to_string(), input.datapoint_id.clone());
+            PerformanceOutcome {
+                user_id: user_id.clone(),
+                message,
+                metadata,
+            }
+        })
+        .collect()
+}
 
-        push_notification_to_user_do(
-            env,
-            user_id,
-            NotificationType::Performance,
-            &message,
-            Some(metadata),
-        )
-        .await?;
+#[derive(Serialize, Debug)]
+pub struct FailedDelivery {
+    pub user_id: String,
+    pub error: String,
+}
+
+/// Outcome of paying out a task result. Players in `failed` still need their
+/// reward; they are queued for the cron, and sending the same result again is safe.
+#[derive(Serialize, Debug, Default)]
+pub struct DeliveryReport {
+    pub datapoint_id: String,
+    pub delivered: Vec<String>,
+    pub already_applied: Vec<String>,
+    pub failed: Vec<FailedDelivery>,
+}
+
+impl DeliveryReport {
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
     }
+}
 
-    Ok(())
+pub async fn notify_task_result(input: &TaskResultInput, env: &Env) -> DeliveryReport {
+    let mut report = DeliveryReport {
+        datapoint_id: input.datapoint_id.clone(),
+        ..Default::default()
+    };
+
+    for outcome in performance_outcomes(input) {
+        let user_id = outcome.user_id.clone();
+        match deliver_task_reward(env, &input.datapoint_id, outcome).await {
+            Ok(true) => report.delivered.push(user_id),
+            Ok(false) => report.already_applied.push(user_id),
+            Err(e) => report.failed.push(FailedDelivery {
+                user_id,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    report
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(akai_balance: usize, iq: usize, social_score: usize) -> Progress {
+        Progress {
+            iq,
+            social_score,
+            product: 0,
+            all_task_done: false,
+            akai_balance,
+            total_task_completed: 0,
+            streak: 0,
+            badges: Vec::new(),
+            streak_last_day: None,
+            streak_freezes: 0,
+            best_streak: 0,
+        }
+    }
+
+    fn metadata(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_signed_deltas() {
+        let m = metadata(&[
+            ("akai_balance", "-10"),
+            ("iq", "-15"),
+            ("social_score", "3"),
+        ]);
+        let payload = RewardPayload::from_metadata(Some(&m), RewardPayload::default());
+        assert_eq!(
+            payload,
+            RewardPayload {
+                akai_balance: -10,
+                iq: -15,
+                social_score: 3
+            }
+        );
+    }
+
+    #[test]
+    fn missing_or_malformed_keys_use_defaults() {
+        let defaults = RewardPayload {
+            akai_balance: 25,
+            iq: 0,
+            social_score: 10,
+        };
+        let m = metadata(&[("akai_balance", "lots")]);
+        assert_eq!(RewardPayload::from_metadata(Some(&m), defaults), defaults);
+        assert_eq!(RewardPayload::from_metadata(None, defaults), defaults);
+    }
+
+    #[test]
+    fn reward_is_added() {
+        let mut p = progress(5, 100, 1);
+        let applied = RewardPayload {
+            akai_balance: 20,
+            iq: 10,
+            social_score: 2,
+        }
+        .apply(&mut p);
+
+        assert_eq!((p.akai_balance, p.iq, p.social_score), (25, 110, 3));
+        assert_eq!(
+            applied,
+            RewardPayload {
+                akai_balance: 20,
+                iq: 10,
+                social_score: 2
+            }
+        );
+    }
+
+    #[test]
+    fn penalty_is_subtracted() {
+        let mut p = progress(50, 100, 0);
+        let applied = RewardPayload {
+            akai_balance: -10,
+            iq: -15,
+            social_score: 0,
+        }
+        .apply(&mut p);
+
+        assert_eq!((p.akai_balance, p.iq), (40, 85));
+        assert_eq!((applied.akai_balance, applied.iq), (-10, -15));
+    }
+
+    #[test]
+    fn penalty_saturates_at_zero_and_reports_what_was_taken() {
+        let mut p = progress(4, 0, 0);
+        let applied = RewardPayload {
+            akai_balance: -10,
+            iq: -15,
+            social_score: 0,
+        }
+        .apply(&mut p);
+
+        assert_eq!((p.akai_balance, p.iq), (0, 0));
+        assert_eq!((applied.akai_balance, applied.iq), (-4, 0));
+    }
+
+    #[test]
+    fn reward_saturates_at_max() {
+        let mut p = progress(usize::MAX - 1, 0, 0);
+        let applied = RewardPayload {
+            akai_balance: 20,
+            ..Default::default()
+        }
+        .apply(&mut p);
+
+        assert_eq!(p.akai_balance, usize::MAX);
+        assert_eq!(applied.akai_balance, 1);
+    }
+
+    #[test]
+    fn flagged_players_get_penalties() {
+        let outcomes = performance_outcomes(&TaskResultInput {
+            player_ranking: vec!["a".into(), "b".into(), "c".into()],
+            flagged_players: vec!["b".into()],
+            datapoint_id: "dp".into(),
+            difficulty: None,
+        });
+        let payload = |user_id: &str| {
+            let outcome = outcomes.iter().find(|o| o.user_id == user_id).unwrap();
+            RewardPayload::from_metadata(Some(&outcome.metadata), RewardPayload::default())
+        };
+
+        assert_eq!((payload("a").akai_balance, payload("a").iq), (20, 10));
+        assert_eq!((payload("b").akai_balance, payload("b").iq), (-10, -15));
+        assert_eq!((payload("c").akai_balance, payload("c").iq), (15, 7));
+    }
+}
@@ -1,19 +1,48 @@
-use crate::daily_task::Links;
+use crate::account::send_verification_email;
+use crate::labeling_client::{LabelRequest, LabelingBackend, LabelingClient, LabelingError};
+use crate::link_tracking::click_url;
+use crate::friends::{
+    accept_friend_request, add_accepted_friendship, list_friends, remove_friendship,
+    send_friend_request, RequestOutcome,
+};
 use crate::gpt_voice::*;
-use crate::notification::{push_notification_to_user_do, NotificationType};
-use crate::types::DurableObjectAugmentedMsg;
-use crate::utils::{fetch_video_tasks, find_user_id_by_referral_code, give_daily_reward};
+use crate::mailer::Mailer;
+use crate::consensus::{queue_failed_deliveries, record_label, resolve_if_ready};
+use crate::skill::{updated_skill, DEFAULT_DIFFICULTY};
+use crate::streak::{self, MAX_STREAK_FREEZES, STREAK_FREEZE_COST};
+use crate::video_pool::assign_tasks;
+use crate::quests::{emit, refresh_quests, GameEvent};
+use crate::reward_track::RewardTrack;
+use crate::notification::{
+    deliver_task_reward, new_notification, performance_outcomes, push_notification_to_user_do,
+    task_reward_key, FailedDelivery, Notification, NotificationType, RewardPayload,
+    TaskResultInput,
+};
+use crate::referral::{
+    credit_referrer, delete_redemption, generate_unique_referral_code, get_redemption,
+    mark_credited, milestone_reached, record_redemption, referral_code_taken, referral_milestone,
+    referral_overview, set_referral_code, REFEREE_AKAI_REWARD, REFEREE_SOCIAL_REWARD, REFERRER_AKAI_REWARD,
+    REFERRER_SOCIAL_REWARD,
+};
+use crate::types::{AdminOp, DurableObjectAugmentedMsg, MAX_APPLIED_REWARDS};
+use crate::utils::{find_user_id_by_referral_code, is_registered};
+use crate::validation::{
+    validate_email, validate_label_submission, validate_password, validate_user_name,
+    validate_utc_offset, validate_vanity_code,
+};
 use crate::{daily_task::*, gpt_voice};
 use rand::Rng;
 use serde_json::json;
 use sha2::digest::Update;
 use sha2::Digest;
 use std::collections::HashMap;
-use worker::*;
-use worker::{console_error, console_log, D1Database, Date, Env, Response, Result};
+use worker::{D1Database, Date, Env, Response, Result};
 
 use crate::{
-    sql::insert_new_user,
+    sql::{
+        delete_user, email_taken, export_user_rows, insert_new_user, login_name_taken, update_email, update_password,
+        update_user_name,
+    },
     types::{Op, PowerUpKind, UserData, WsMsg},
     utils::calculate_king_alien_lvl,
     utils::calculate_product,
@@ -33,6 +62,14 @@ impl UserData {
                 if idx_a == idx_b {
                     return Response::error("Combined Alien IDs cannot be the same", 400);
                 }
+                let aliens = &self.game_state.active_aliens;
+                let (Some(&level_a), Some(&level_b)) = (aliens.get(*idx_a), aliens.get(*idx_b))
+                else {
+                    return Response::error("Invalid alien index", 400);
+                };
+                if level_a == 0 || level_a != level_b {
+                    return Response::error("Only two aliens of the same level can be combined", 400);
+                }
                 self.game_state.active_aliens[*idx_a] += 1;
                 // Replace the second alien with (king_lvl-1)*10 + 1 if we have inventory
                 self.game_state.active_aliens[*idx_b] = if self.game_state.inventory_aliens > 0 {
@@ -42,13 +79,7 @@ impl UserData {
                     0
                 };
                 self.game_state.total_merged_aliens += 1;
-                //daily task check
-                self.daily.daily_merge.0 += 1;
-                if (self.daily.daily_merge.0 == self.daily.daily_merge.1) {
-                    self.daily.daily_merge.2 = true;
-                    self.daily.total_completed += 1;
-                    self.progress.social_score += 2;
-                }
+                emit(self, GameEvent::AlienMerged);
                 calculate_king_alien_lvl(self);
                 Response::ok(
                     json!({
@@ -166,12 +197,7 @@ impl UserData {
                 }
 
                 calculate_king_alien_lvl(self);
-                self.daily.daily_powerups.0 += 1;
-                if (self.daily.daily_powerups.0 == self.daily.daily_powerups.1) {
-                    self.daily.daily_powerups.2 = true;
-                    self.daily.total_completed += 1;
-                    self.progress.social_score += 2;
-                }
+                emit(self, GameEvent::PowerUpUsed);
 
                 Response::ok(
                     json!({
@@ -199,7 +225,21 @@ impl UserData {
                     .to_string(),
                 )
             }
-            Op::GetData => {
+            Op::BuyStreakFreeze => {
+                if self.progress.streak_freezes >= MAX_STREAK_FREEZES {
+                    return Response::error("Already holding the maximum streak freezes", 409);
+                }
+                if self.progress.akai_balance < STREAK_FREEZE_COST {
+                    return Response::error("Not enough Akai", 402);
+                }
+                self.progress.akai_balance -= STREAK_FREEZE_COST;
+                self.progress.streak_freezes += 1;
+                Response::from_json(&json!({
+                    "streak_freezes": self.progress.streak_freezes,
+                    "akai_balance": self.progress.akai_balance
+                }))
+            }
+            Op::GetData | Op::GetDataInternal => {
                 let current_time = Date::now().as_millis() / 1000;
                 let time_since_last_login = current_time - self.profile.real_login;
                 let one_hour = 15;
@@ -226,17 +266,34 @@ impl UserData {
                 .to_string(),
             ),
             Op::Register(password) => {
-                console_log!("Creating tables if not exists");
                 let sha256 = sha2::Sha256::new();
                 let password = sha256.chain(password.as_bytes()).finalize();
                 let password = hex::encode(password);
 
                 self.profile.password = Some(password.clone());
 
+                // A user id equal to someone's username would make logins ambiguous
+                match login_name_taken(d1, &op_request.user_id, &op_request.user_id).await {
+                    Ok(false) => {}
+                    Ok(true) => return Response::error("User id already taken", 409),
+                    Err(e) => {
+                        log_op_error(op_request, "DB error during user id lookup", &e);
+                        return Response::error("Registration failed", 500);
+                    }
+                }
+
+                match generate_unique_referral_code(d1, &op_request.user_id).await {
+                    Ok(code) => self.social.referal_code = code,
+                    Err(e) => {
+                        log_op_error(op_request, "Referral code generation failed", &e);
+                        return Response::error("Registration failed", 500);
+                    }
+                }
+
                 match insert_new_user(&self, &d1).await {
                     Ok(_) => Response::ok("User registered successfully!"),
                     Err(e) => {
-                        console_error!("Registration failed: {:?}", e);
+                        log_op_error(op_request, "Registration failed", &e);
                         Response::error("Registration failed", 500)
                     }
                 }
@@ -244,10 +301,57 @@ impl UserData {
 
             // Profile operations
             Op::UpdateEmail(email) => {
+                if let Err(reason) = validate_email(email) {
+                    return Response::error(reason, 400);
+                }
+
+                match email_taken(d1, email, &op_request.user_id).await {
+                    Ok(false) => {}
+                    Ok(true) => return Response::error("Email already in use", 409),
+                    Err(e) => {
+                        log_op_error(op_request, "DB error during email lookup", &e);
+                        return Response::error("Database error", 500);
+                    }
+                }
+
+                // Persisted right away: password reset looks accounts up by email in D1
+                if let Err(e) = update_email(d1, &op_request.user_id, email, false).await {
+                    log_op_error(op_request, "Failed to update email", &e);
+                    return Response::error("Database error", 500);
+                }
                 self.profile.email = Some(email.clone());
+                self.profile.email_verified = false;
+
+                let mailer = Mailer::from_env(env);
+                if let Err(e) =
+                    send_verification_email(d1, env, &mailer, &op_request.user_id, email).await
+                {
+                    log_op_error(op_request, "Failed to send verification email", &e);
+                }
+
                 Response::ok(
                     json!({
-                        "email": self.profile.email
+                        "email": self.profile.email,
+                        "email_verified": self.profile.email_verified
+                    })
+                    .to_string(),
+                )
+            }
+            Op::MarkEmailVerified(email) => {
+                // The address may have changed since the link was mailed
+                if self.profile.email.as_deref() != Some(email.as_str()) {
+                    return Response::error("Email no longer matches", 409);
+                }
+                if let Err(e) = update_email(d1, &op_request.user_id, email, true).await {
+                    log_op_error(op_request, "Failed to mark email verified", &e);
+                    return Response::error("Database error", 500);
+                }
+                self.profile.email_verified = true;
+
+                Response::ok(
+                    json!({
+                        "email": self.profile.email,
+                        "email_verified": self.profile.email_verified
                     })
                     .to_string(),
                 )
@@ -262,6 +366,28 @@ impl UserData {
                 )
             }
 
+            Op::UpdateTimezone(offset) => {
+                if let Err(reason) = validate_utc_offset(*offset) {
+                    return Response::error(reason, 400);
+                }
+                let now = Date::now().as_millis() / 1000;
+                if *offset != self.profile.utc_offset_minutes
+                    && now < self.profile.timezone_changed_at + TIMEZONE_CHANGE_COOLDOWN_SECS
+                {
+                    return Response::error("Timezone can only be changed once a week", 429);
+                }
+
+                if *offset != self.profile.utc_offset_minutes {
+                    self.profile.utc_offset_minutes = *offset;
+                    self.profile.timezone_changed_at = now;
+                }
+                // Today's tasks keep their reset time; the new offset applies from the next day
+                Response::from_json(&json!({
+                    "utc_offset_minutes": self.profile.utc_offset_minutes,
+                    "resets_at": self.daily.resets_at
+                }))
+            }
+
             // Progress operations
             Op::UpdateIq(iq) => {
                 self.progress.iq = *iq;
@@ -308,6 +434,30 @@ impl UserData {
                 )
             }
             Op::UpdateUserName(user_name) => {
+                if let Some(name) = user_name {
+                    if let Err(reason) = validate_user_name(name) {
+                        return Response::error(reason, 400);
+                    }
+
+                    match login_name_taken(d1, name, &op_request.user_id).await {
+                        Ok(false) => {}
+                        Ok(true) => return Response::error("Username already taken", 409),
+                        Err(e) => {
+                            log_op_error(op_request, "DB error during username lookup", &e);
+                            return Response::error("Database error", 500);
+                        }
+                    }
+                }
+
+                // Persist right away so the new name works as a login immediately
+                if let Err(e) =
+                    update_user_name(d1, &op_request.user_id, user_name.as_deref()).await
+                {
+                    // Unique index violation from a concurrent rename
+                    log_op_error(op_request, "Failed to update username", &e);
+                    return Response::error("Username already taken", 409);
+                }
+
                 self.profile.user_name = user_name.clone();
 
                 Response::ok(
@@ -317,11 +467,37 @@ impl UserData {
                     .to_string(),
                 )
             }
-            Op::UpdatePassword(password) => {
+            Op::RenameInternal(user_name) => {
+                if let Err(e) = update_user_name(d1, &op_request.user_id, Some(user_name)).await {
+                    log_op_error(op_request, "Failed to rename user", &e);
+                    return Response::error("Database error", 500);
+                }
+                self.profile.user_name = Some(user_name.clone());
+                Response::ok("Username updated")
+            }
+            Op::SetReferralCodeInternal(code) => {
+                if let Err(e) = set_referral_code(d1, &op_request.user_id, code).await {
+                    log_op_error(op_request, "Failed to replace referral code", &e);
+                    return Response::error("Database error", 500);
+                }
+                self.social.referal_code = code.clone();
+                Response::ok("Referral code updated")
+            }
+            Op::UpdatePassword(password) | Op::ResetPassword(password) => {
+                if let Err(reason) = validate_password(password) {
+                    return Response::error(reason, 400);
+                }
+
                 let sha256 = sha2::Sha256::new();
                 let password = sha256.chain(password.as_bytes()).finalize();
                 let password = hex::encode(password);
 
+                // Logins check D1, so the new password has to land there now
+                if let Err(e) = update_password(d1, &op_request.user_id, &password).await {
+                    log_op_error(op_request, "Failed to update password", &e);
+                    return Response::error("Database error", 500);
+                }
+
                 self.profile.password = Some(password.clone());
 
                 Response::ok(
@@ -346,30 +522,13 @@ impl UserData {
                 )
             }
             Op::AddNotificationInternal(notification) => {
-                if notification.notification_type == NotificationType::Referral {
-                    self.social.players_referred += 1;
-                    self.progress.social_score += 10;
-                    self.progress.akai_balance += 50;
-                } else if notification.notification_type == NotificationType::Performance {
-                    if let Some(metadata) = &notification.metadata {
-                        if let Some(akai_str) = metadata.get("akai_balance") {
-                            if let Ok(akai) = akai_str.parse::<usize>() {
-                                self.progress.akai_balance += akai;
-                            }
-                        }
-
-                        if let Some(iq_str) = metadata.get("iq") {
-                            if let Ok(iq) = iq_str.parse::<usize>() {
-                                self.progress.iq += iq;
-                            }
-                        }
-                    }
-                }
-                self.notifications.push(notification.clone());
+                let applied = self.add_notification(notification.clone());
                 Response::ok(
                     json!({
                         "status": "Notification added to DO",
-                        "players_referred": self.social.players_referred
+                        "players_referred": self.social.players_referred,
+                        "duplicate": applied.is_none(),
+                        "applied": applied.unwrap_or_default()
                     })
                     .to_string(),
                 )
@@ -399,42 +558,232 @@ impl UserData {
                 }
             }
             Op::UseReferralCode(code) => {
-                let env = env.clone();
+                let referrer_user_id = match find_user_id_by_referral_code(d1, code).await {
+                    Ok(Some(referrer_user_id)) => referrer_user_id,
+                    Ok(None) => return Response::error("Invalid referral code", 404),
+                    Err(e) => {
+                        log_op_error(op_request, "DB error during referral lookup", &e);
+                        return Response::error("Database error", 500);
+                    }
+                };
+
+                if referrer_user_id == op_request.user_id {
+                    return Response::error("Cannot use your own referral code", 400);
+                }
+
+                if self.social.referred_by.is_some() {
+                    return Response::error("Referral code already redeemed", 409);
+                }
+
+                match get_redemption(d1, &op_request.user_id).await {
+                    Ok(None) => {}
+                    Ok(Some(_)) => return Response::error("Referral code already redeemed", 409),
+                    Err(e) => {
+                        log_op_error(op_request, "DB error during redemption lookup", &e);
+                        return Response::error("Database error", 500);
+                    }
+                }
+
+                let credit_now = milestone_reached(self, referral_milestone(env));
+
+                if let Err(e) =
+                    record_redemption(d1, &op_request.user_id, &referrer_user_id, code).await
+                {
+                    // Most likely a concurrent redemption hitting the primary key
+                    log_op_error(op_request, "Failed to record referral redemption", &e);
+                    return Response::error("Referral code already redeemed", 409);
+                }
+
+                if credit_now {
+                    if let Err(e) = credit_referrer(
+                        env,
+                        d1,
+                        &referrer_user_id,
+                        &op_request.user_id,
+                        &op_request.request_id,
+                    )
+                    .await
+                    {
+                        // Give the code back so the player can retry; rewards
+                        // already paid are skipped then
+                        log_op_error(op_request, "Failed to push referral notification", &e);
+                        if let Err(e) = delete_redemption(d1, &op_request.user_id).await {
+                            log_op_error(op_request, "Failed to undo referral redemption", &e);
+                        }
+                        return Response::error("Internal error", 500);
+                    }
+                    if let Err(e) = mark_credited(d1, &op_request.user_id).await {
+                        log_op_error(op_request, "Failed to mark referral credited", &e);
+                    }
+                } else {
+                    self.social.pending_referrer = Some(referrer_user_id.clone());
+                }
+
+                self.social.referred_by = Some(referrer_user_id.clone());
+                self.progress.akai_balance += REFEREE_AKAI_REWARD;
+                self.progress.social_score += REFEREE_SOCIAL_REWARD;
+                calculate_product(self);
+
+                // Referrer and referee start out as friends
+                if let Err(e) =
+                    add_accepted_friendship(d1, &referrer_user_id, &op_request.user_id).await
+                {
+                    log_op_error(op_request, "Failed to auto-friend referral", &e);
+                }
+
+                Response::ok(
+                    json!({
+                        "status": "Referral recorded",
+                        "referrer": referrer_user_id,
+                        "referrer_credited": credit_now,
+                        "akai_balance": self.progress.akai_balance,
+                        "social_score": self.progress.social_score
+                    })
+                    .to_string(),
+                )
+            }
+            Op::ClaimVanityCode(code) => {
+                if let Err(reason) = validate_vanity_code(code) {
+                    return Response::error(reason, 400);
+                }
+
+                match referral_code_taken(d1, code, &op_request.user_id).await {
+                    Ok(false) => {}
+                    Ok(true) => return Response::error("Referral code already taken", 409),
+                    Err(e) => {
+                        log_op_error(op_request, "DB error during referral code lookup", &e);
+                        return Response::error("Database error", 500);
+                    }
+                }
+
+                if let Err(e) = set_referral_code(d1, &op_request.user_id, code).await {
+                    // Unique index violation from a concurrent claim
+                    log_op_error(op_request, "Failed to claim referral code", &e);
+                    return Response::error("Referral code already taken", 409);
+                }
+
+                self.social.referal_code = code.clone();
+                Response::ok(
+                    json!({
+                        "referal_code": self.social.referal_code
+                    })
+                    .to_string(),
+                )
+            }
+            Op::SendFriendRequest(friend_id) => {
+                if *friend_id == op_request.user_id {
+                    return Response::error("Cannot befriend yourself", 400);
+                }
+                if !is_registered(d1, friend_id).await {
+                    return Response::error("User not found", 404);
+                }
+
+                let outcome = match send_friend_request(d1, &op_request.user_id, friend_id).await
+                {
+                    Ok(outcome) => outcome,
+                    Err(e) => {
+                        log_op_error(op_request, "DB error during friend request", &e);
+                        return Response::error("Database error", 500);
+                    }
+                };
+
+                let (status, message) = match outcome {
+                    RequestOutcome::AlreadyFriends => {
+                        return Response::error("Already friends", 409)
+                    }
+                    RequestOutcome::AlreadyPending => {
+                        return Response::error("Friend request already sent", 409)
+                    }
+                    RequestOutcome::Sent => ("Friend request sent", "sent you a friend request"),
+                    RequestOutcome::Accepted => {
+                        ("Friend request accepted", "accepted your friend request")
+                    }
+                };
+
+                let mut metadata = HashMap::new();
+                metadata.insert("from".to_string(), op_request.user_id.clone());
+                if let Err(e) = push_notification_to_user_do(
+                    env,
+                    friend_id,
+                    NotificationType::Friend,
+                    &format!("{} {}", self.display_name(), message),
+                    Some(metadata),
+                )
+                .await
+                {
+                    log_op_error(op_request, "Failed to push friend notification", &e);
+                }
 
-                match find_user_id_by_referral_code(&d1, code).await {
-                    Ok(Some(referrer_user_id)) => {
-                        let message = "Your referral code was used!";
+                Response::ok(
+                    json!({
+                        "status": status,
+                        "friend_id": friend_id
+                    })
+                    .to_string(),
+                )
+            }
+            Op::AcceptFriendRequest(requester_id) => {
+                match accept_friend_request(d1, &op_request.user_id, requester_id).await {
+                    Ok(true) => {
                         let mut metadata = HashMap::new();
-                        metadata.insert("used_by".to_string(), op_request.user_id.clone());
-                        metadata.insert("social_score".to_string(), "10".to_string());
-                        metadata.insert("akai_balance".to_string(), "25".to_string());
+                        metadata.insert("from".to_string(), op_request.user_id.clone());
                         if let Err(e) = push_notification_to_user_do(
-                            &env,
-                            &referrer_user_id,
-                            NotificationType::Referral,
-                            message,
+                            env,
+                            requester_id,
+                            NotificationType::Friend,
+                            &format!("{} accepted your friend request", self.display_name()),
                             Some(metadata),
                         )
                         .await
                         {
-                            console_error!("Failed to push referral notification: {:?}", e);
-                            return Response::error("Internal error", 500);
+                            log_op_error(op_request, "Failed to push friend notification", &e);
                         }
+
                         Response::ok(
                             json!({
-                                "status": "Referral recorded",
-                                "referrer": referrer_user_id
+                                "status": "Friend request accepted",
+                                "friend_id": requester_id
                             })
                             .to_string(),
                         )
                     }
-                    Ok(None) => Response::error("Invalid referral code", 404),
+                    Ok(false) => Response::error("Friend request not found", 404),
                     Err(e) => {
-                        console_error!("DB error during referral lookup: {:?}", e);
+                        log_op_error(op_request, "DB error accepting friend request", &e);
                         Response::error("Database error", 500)
                     }
                 }
             }
+            Op::RemoveFriend(friend_id) => {
+                match remove_friendship(d1, &op_request.user_id, friend_id).await {
+                    Ok(true) => Response::ok(
+                        json!({
+                            "status": "Friend removed",
+                            "friend_id": friend_id
+                        })
+                        .to_string(),
+                    ),
+                    Ok(false) => Response::error("Friend not found", 404),
+                    Err(e) => {
+                        log_op_error(op_request, "DB error removing friend", &e);
+                        Response::error("Database error", 500)
+                    }
+                }
+            }
+            Op::GetReferrals => match referral_overview(d1, &op_request.user_id).await {
+                Ok(overview) => Response::from_json(&overview),
+                Err(e) => {
+                    log_op_error(op_request, "DB error listing referrals", &e);
+                    Response::error("Database error", 500)
+                }
+            },
+            Op::GetFriends => match list_friends(d1, &op_request.user_id).await {
+                Ok(friends) => Response::from_json(&json!({ "friends": friends })),
+                Err(e) => {
+                    log_op_error(op_request, "DB error listing friends", &e);
+                    Response::error("Database error", 500)
+                }
+            },
             Op::UpdateDbFromDo => match crate::sql::update_user_data(self, d1).await {
                 Ok(_) => Response::ok(
                     json!({
@@ -443,82 +792,139 @@ impl UserData {
                     .to_string(),
                 ),
                 Err(e) => {
-                    console_error!("Error updating DB from DO: {:?}", e);
+                    log_op_error(op_request, "Error updating DB from DO", &e);
                     Response::error("Failed to update DB", 500)
                 }
             },
             Op::GenerateDailyTasks => {
-                console_log!("100");
-                let now = worker::Date::now().as_millis() as u64 / 1000;
-                let q_seconds = 1000; // 1 day interval
-
-                let last_login = self.profile.last_login;
+                let now = worker::Date::now().as_millis() / 1000;
 
-                // if now - last_login < q_seconds && !self.daily.links.is_empty() {
-                //     return Response::from_json(&self.daily);
-                // }
+                // Once per local day; asking again returns the day's tasks
+                let offset = self.profile.utc_offset_minutes;
+                let today = local_day(now as i64, offset);
+                if self.daily.day.as_ref().is_some_and(|day| *day >= today) {
+                    return Response::from_json(&self.daily);
+                }
 
-                // if self.daily.total_completed < 3 && self.daily.links.len() > 0 {
-                //     self.progress.social_score -= 5;
-                // }
+                let penalty = IncompletePenalty::from_env(env).penalty_for(&self.daily);
+                self.progress.social_score = self.progress.social_score.saturating_sub(penalty);
+                let freezes_used = streak::roll_over(self, &today);
+                if freezes_used > 0 {
+                    log_info!(&op_request.request_id, "streak.frozen", "user_id" => op_request.user_id, "freezes_used" => freezes_used);
+                }
 
                 let mut rng = rand::thread_rng();
-                let random_links = get_random_links(2)
-                    .into_iter()
-                    .map(|sl| Links {
-                        url: sl.url,
-                        platform: sl.platform,
-                        visited: false,
-                    })
-                    .collect();
+                let mut random_links = get_random_links(d1, 2).await.unwrap_or_else(|e| {
+                    log_op_error(op_request, "Failed to load daily links", &e);
+                    Vec::new()
+                });
+                for link in &mut random_links {
+                    match click_url(env, &op_request.user_id, link) {
+                        Ok(url) => link.click_url = Some(url),
+                        Err(e) => log_op_error(op_request, "Failed to sign daily link", &e),
+                    }
+                }
 
                 let number_of_videos_to_request = ((self.progress.iq) / 50 + 1) * 5;
 
-                let video_tasks = fetch_video_tasks(number_of_videos_to_request, &env)
-                    .await
-                    .unwrap_or_default();
+                let video_tasks = assign_tasks(
+                    d1,
+                    env,
+                    &op_request.user_id,
+                    self.profile.annotation_skill,
+                    number_of_videos_to_request,
+                    &op_request.request_id,
+                )
+                .await
+                .unwrap_or_else(|e| {
+                    log_op_error(op_request, "Failed to assign video tasks", &e);
+                    Vec::new()
+                });
 
-                console_log!("{}", video_tasks.len());
+                log_debug!(&op_request.request_id, "daily.video_tasks_fetched", "user_id" => op_request.user_id, "count" => video_tasks.len());
 
                 self.daily.links = random_links;
                 self.daily.video_tasks = video_tasks;
+                self.daily.video_tasks_target = number_of_videos_to_request;
                 self.daily.daily_merge = (0, rng.gen_range(2..=4), false);
                 self.daily.daily_annotate = (0, rng.gen_range(3..=7), false);
                 self.daily.daily_powerups = (0, rng.gen_range(2..=6), false);
-                self.profile.last_login = now;
                 self.daily.alien_earned = None;
                 self.daily.pu_earned = None;
+                self.daily.claimed_tiers.clear();
                 self.daily.total_completed = 0;
+                self.daily.day = Some(today);
+                self.daily.resets_at = next_local_midnight(now as i64, offset);
+                self.daily.missed_penalty = penalty;
+
+                if let Err(e) = refresh_quests(self, d1, now as i64).await {
+                    log_op_error(op_request, "Failed to refresh quests", &e);
+                }
 
                 Response::from_json(&self.daily)
             }
+            Op::GetQuests => {
+                let now = worker::Date::now().as_millis() as i64 / 1000;
+                if let Err(e) = refresh_quests(self, d1, now).await {
+                    log_op_error(op_request, "Failed to refresh quests", &e);
+                }
+                Response::from_json(&self.quests)
+            }
 
-            Op::CheckDailyTask(maybe_url) => {
-                let mut matched = false;
-
-                if let Some(url) = maybe_url {
-                    for link in &mut self.daily.links {
-                        if link.url == *url && !link.visited {
-                            link.visited = true;
-                            self.progress.social_score += 2;
-                            matched = true;
-                            break; // Exit loop once matched and updated
-                        }
+            // The client's word is no longer enough, see RecordLinkVisit
+            Op::CheckDailyTask(_) => {
+                let missing = self
+                    .daily
+                    .video_tasks_target
+                    .saturating_sub(self.daily.video_tasks.len());
+                if missing > 0 {
+                    let assigned = assign_tasks(
+                        d1,
+                        env,
+                        &op_request.user_id,
+                        self.profile.annotation_skill,
+                        missing,
+                        &op_request.request_id,
+                    )
+                    .await;
+                    match assigned {
+                        Ok(tasks) => self.daily.video_tasks.extend(tasks),
+                        Err(e) => log_op_error(op_request, "Failed to top up video tasks", &e),
                     }
+                }
+                Response::from_json(&self.daily)
+            }
+            Op::RecordLinkVisit(url) => {
+                let Some(link) = self
+                    .daily
+                    .links
+                    .iter_mut()
+                    .find(|link| link.url == *url)
+                else {
+                    return Response::error("Link is not part of today's tasks", 404);
+                };
 
-                    if matched {
-                        self.daily.total_completed += 1;
-                    }
+                if !link.visited {
+                    link.visited = true;
+                    self.progress.social_score += link.reward;
+                    emit(self, GameEvent::LinkVisited);
                 }
                 Response::from_json(&self.daily)
             }
             Op::ClaimDailyReward(index) => {
-                give_daily_reward(self, *index);
+                let rewards = match RewardTrack::from_env(env).claim(self, *index) {
+                    Ok(rewards) => rewards,
+                    Err(e) => return Response::error(e.message(), e.status()),
+                };
                 Response::ok(
                     json!({
+                        "rewards": rewards,
+                        "claimed_tiers": self.daily.claimed_tiers,
                         "active_aliens": self.game_state.active_aliens,
                         "king_lvl": self.game_state.king_lvl,
                         "product": self.progress.product,
+                        "akai_balance": self.progress.akai_balance,
+                        "badges": self.progress.badges,
                         "alien_earned": self.daily.alien_earned,
                         "pu_earned": self.daily.pu_earned,
                         "power_ups": self.game_state.power_ups
@@ -526,48 +932,249 @@ impl UserData {
                     .to_string(),
                 )
             }
+            Op::DeleteAccount(password) => {
+                let sha256 = sha2::Sha256::new();
+                let password = hex::encode(sha256.chain(password.as_bytes()).finalize());
+                if self.profile.password.as_deref() != Some(password.as_str()) {
+                    return Response::error("Invalid password", 401);
+                }
+
+                // The DO storage is wiped by the caller once this succeeds
+                match delete_user(d1, &op_request.user_id).await {
+                    Ok(_) => Response::ok(
+                        json!({
+                            "status": "Account deleted"
+                        })
+                        .to_string(),
+                    ),
+                    Err(e) => {
+                        log_op_error(op_request, "Failed to delete account", &e);
+                        Response::error("Failed to delete account", 500)
+                    }
+                }
+            }
+            Op::ExportMyData => {
+                let tables = match export_user_rows(d1, &op_request.user_id).await {
+                    Ok(tables) => tables,
+                    Err(e) => {
+                        log_op_error(op_request, "Failed to export user rows", &e);
+                        return Response::error("Failed to export data", 500);
+                    }
+                };
+
+                Response::from_json(&json!({
+                    "user_id": op_request.user_id,
+                    "exported_at": Date::now().as_millis() / 1000,
+                    "durable_object": self.without_secrets(),
+                    "database": tables
+                }))
+            }
+            Op::Admin(admin_op) => {
+                match admin_op {
+                    AdminOp::Inspect => return Response::from_json(&self.without_secrets()),
+                    AdminOp::Replace(new_data) => {
+                        // Name, email and password have their own checked paths
+                        let mut new_data = (**new_data).clone();
+                        new_data.profile.user_id = self.profile.user_id.clone();
+                        new_data.profile.user_name = self.profile.user_name.clone();
+                        new_data.profile.email = self.profile.email.clone();
+                        new_data.profile.email_verified = self.profile.email_verified;
+                        new_data.profile.password = self.profile.password.clone();
+                        // Keep paid rewards paid
+                        new_data.applied_rewards = std::mem::take(&mut self.applied_rewards);
+                        *self = new_data;
+                    }
+                    AdminOp::Grant(grant) => self.apply_grant(grant, false),
+                    AdminOp::Revoke(grant) => self.apply_grant(grant, true),
+                }
+
+                calculate_product(self);
+
+                if let Err(e) = crate::sql::update_user_data(self, d1).await {
+                    log_op_error(op_request, "Error syncing admin change", &e);
+                    return Response::error("Failed to sync data", 500);
+                }
+                Response::from_json(&self.without_secrets())
+            }
             Op::SyncData => match crate::sql::update_user_data(self, d1).await {
                 Ok(_) => Response::ok("Data synced successfully"),
                 Err(e) => {
-                    console_error!("Error syncing data: {:?}", e);
+                    log_op_error(op_request, "Error syncing data", &e);
                     Response::error("Failed to sync data", 500)
                 }
             },
             Op::SubmitVideoLabel(datapoint_id, label) => {
-                let payload = serde_json::json!({
-                    "datapointId": datapoint_id,
-                    "label": label
-                })
-                .to_string();
-
-                let req = Request::new_with_init(
-                    "http://localhost:3001/api/game/label-datapoint",
-                    &RequestInit {
-                        method: Method::Post,
-                        body: Some(payload.into()),
-                        headers: {
-                            let mut headers = Headers::new();
-                            headers.set("Content-Type", "application/json")?;
-                            headers
-                        },
-                        ..Default::default()
-                    },
-                )?;
-
-                let res = Fetch::Request(req).send().await?;
-                let status = res.status_code();
-
-                if status >= 200 && status < 300 {
-                    Response::from_json(&serde_json::json!({
-                        "message": "Label submitted successfully"
-                    }))
-                } else {
-                    Response::from_json(&serde_json::json!({
+                let Some(task) = self
+                    .daily
+                    .video_tasks
+                    .iter()
+                    .find(|task| task.id == *datapoint_id)
+                else {
+                    return Response::error("Datapoint is not in today's tasks", 404);
+                };
+                if task.visited {
+                    return Response::error("Datapoint already labeled", 409);
+                }
+                if let Err(reason) = validate_label_submission(label, &task.pre_label) {
+                    return Response::error(reason, 400);
+                }
+
+                let request = LabelRequest {
+                    datapoint_id: datapoint_id.clone(),
+                    label: label.clone(),
+                    corrected_label: label.corrected(&task.pre_label),
+                };
+                let idempotency_key = format!("{}:{}", op_request.user_id, datapoint_id);
+                let submitted = LabelingClient::from_env(env)
+                    .submit_label(&request, &idempotency_key)
+                    .await;
+
+                if let Err(e) = submitted {
+                    log_op_error(op_request, "Failed to submit label", &e);
+                    let status = match e {
+                        LabelingError::Status(status) => Some(status),
+                        _ => None,
+                    };
+                    return Response::from_json(&serde_json::json!({
                         "error": "Failed to submit label",
                         "status": status
-                    }))
+                    }));
+                }
+
+                if let Some(task) = self
+                    .daily
+                    .video_tasks
+                    .iter_mut()
+                    .find(|task| task.id == *datapoint_id)
+                {
+                    task.visited = true;
+                }
+                emit(self, GameEvent::DatapointLabeled);
+
+                // Scoring problems never fail the submission itself
+                if let Err(e) = record_label(d1, &op_request.user_id, &request).await {
+                    log_op_error(op_request, "Failed to store label", &e);
+                }
+                match resolve_if_ready(d1, env, datapoint_id).await {
+                    Ok(Some(result)) => self.pay_out_task_result(op_request, d1, env, &result).await,
+                    Ok(None) => {}
+                    Err(e) => log_op_error(op_request, "Failed to score datapoint", &e),
                 }
+
+                Response::from_json(&serde_json::json!({
+                    "message": "Label submitted successfully",
+                    "daily_annotate": self.daily.daily_annotate,
+                    "total_completed": self.daily.total_completed,
+                    "social_score": self.progress.social_score
+                }))
+            }
+        }
+    }
+
+    /// Deliver a scored datapoint's rewards. The submitting player's share is
+    /// applied here, since a notification sent to this DO from inside one of
+    /// its own ops would be overwritten when the op's state is saved.
+    async fn pay_out_task_result(
+        &mut self,
+        op_request: &DurableObjectAugmentedMsg,
+        d1: &D1Database,
+        env: &Env,
+        result: &TaskResultInput,
+    ) {
+        log_info!(&op_request.request_id, "consensus.resolved", "datapoint_id" => result.datapoint_id, "labels" => result.player_ranking.len(), "flagged" => result.flagged_players.len());
+
+        let mut failed = Vec::new();
+        for outcome in performance_outcomes(result) {
+            if outcome.user_id == op_request.user_id {
+                let mut notification = new_notification(
+                    &outcome.user_id,
+                    NotificationType::Performance,
+                    &outcome.message,
+                    Some(outcome.metadata),
+                );
+                notification.reward_key =
+                    Some(task_reward_key(&result.datapoint_id, &outcome.user_id));
+                self.add_notification(notification);
+                continue;
+            }
+
+            let user_id = outcome.user_id.clone();
+            if let Err(e) = deliver_task_reward(env, &result.datapoint_id, outcome).await {
+                log_error!(&op_request.request_id, "consensus.payout_failed", "datapoint_id" => result.datapoint_id, "user_id" => user_id, "error" => e.to_string());
+                failed.push(FailedDelivery {
+                    user_id,
+                    error: e.to_string(),
+                });
+            }
+        }
+
+        // The cron retries these from the stored result
+        if !failed.is_empty() {
+            if let Err(e) = queue_failed_deliveries(d1, &result.datapoint_id, &failed).await {
+                log_op_error(op_request, "Failed to queue task reward deliveries", &e);
             }
         }
     }
+
+    /// Apply the rewards a notification carries and keep it in the inbox.
+    /// Returns the balance changes actually made, or `None` if the
+    /// notification's reward key was already applied.
+    fn add_notification(&mut self, notification: Notification) -> Option<RewardPayload> {
+        if let Some(key) = &notification.reward_key {
+            if self.applied_rewards.contains(key) {
+                return None;
+            }
+            self.applied_rewards.push(key.clone());
+            let excess = self.applied_rewards.len().saturating_sub(MAX_APPLIED_REWARDS);
+            self.applied_rewards.drain(..excess);
+        }
+
+        let metadata = notification.metadata.as_ref();
+        let applied = match notification.notification_type {
+            NotificationType::Referral => {
+                // Only direct referrals count towards players_referred
+                let tier = metadata
+                    .and_then(|m| m.get("tier"))
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .unwrap_or(1);
+                if tier == 1 {
+                    self.social.players_referred += 1;
+                }
+                let defaults = RewardPayload {
+                    akai_balance: REFERRER_AKAI_REWARD as i64,
+                    iq: 0,
+                    social_score: REFERRER_SOCIAL_REWARD as i64,
+                };
+                RewardPayload::from_metadata(metadata, defaults).apply(&mut self.progress)
+            }
+            NotificationType::Performance => {
+                // Scored datapoints also move the annotation skill
+                if let Some(outcome) = metadata.and_then(|m| m.get("outcome")) {
+                    let difficulty = metadata
+                        .and_then(|m| m.get("difficulty"))
+                        .and_then(|v| v.parse::<f64>().ok())
+                        .unwrap_or(DEFAULT_DIFFICULTY);
+                    let score = if outcome == "agreed" { 1.0 } else { 0.0 };
+                    self.profile.annotation_skill =
+                        updated_skill(self.profile.annotation_skill, difficulty, score);
+                }
+                RewardPayload::from_metadata(metadata, RewardPayload::default())
+                    .apply(&mut self.progress)
+            }
+            NotificationType::System | NotificationType::Friend => RewardPayload::default(),
+        };
+        self.notifications.push(notification);
+        Some(applied)
+    }
+}
+
+fn log_op_error(op_request: &DurableObjectAugmentedMsg, what: &str, e: &impl std::fmt::Debug) {
+    log_error!(
+        &op_request.request_id,
+        "op.error",
+        "op" => op_request.op.kind(),
+        "user_id" => op_request.user_id,
+        "what" => what,
+        "error" => format!("{:?}", e),
+    );
 }
@@ -0,0 +1,688 @@
+// Weekly and monthly quest chains. Chains are defined in the quest_chains
+// table (built-in defaults when it has none), dealt into the player's slots at
+// the start of each period, and advanced by the events ops emit.
+use chrono::{DateTime, Datelike, Duration, NaiveDate};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use worker::{D1Database, Date, Result};
+
+use crate::daily_task::DailyCounter;
+use crate::notification::{new_notification, NotificationType, RewardPayload};
+use crate::streak;
+use crate::types::{PowerUpKind, UserData};
+use crate::utils::{advance_daily_counter, calculate_product};
+
+// Quest slots a player holds at once
+pub const WEEKLY_SLOTS: usize = 3;
+pub const MONTHLY_SLOTS: usize = 2;
+
+pub const MAX_QUEST_STEPS: usize = 10;
+const MAX_QUEST_ID_LEN: usize = 64;
+
+// Merges / power-ups a day that advance quests; daily tasks still count them all
+pub const DAILY_QUEST_MERGES: usize = 30;
+pub const DAILY_QUEST_POWER_UPS: usize = 5;
+
+/// What a quest step counts
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Objective {
+    MergeAliens,
+    UsePowerUps,
+    LabelDatapoints,
+    VisitLinks,
+    // Merge / annotate / power-up targets reached and links visited
+    CompleteDailyTasks,
+    // Days counted towards the streak
+    ExtendStreak,
+}
+
+/// Something a player did, emitted by the op that did it. Aliens and power-ups
+/// can be spawned for free, so only the first few merges and power-ups of a
+/// day count towards quests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GameEvent {
+    AlienMerged,
+    PowerUpUsed,
+    DatapointLabeled,
+    LinkVisited,
+}
+
+impl GameEvent {
+    fn objective(&self) -> Objective {
+        match self {
+            GameEvent::AlienMerged => Objective::MergeAliens,
+            GameEvent::PowerUpUsed => Objective::UsePowerUps,
+            GameEvent::DatapointLabeled => Objective::LabelDatapoints,
+            GameEvent::LinkVisited => Objective::VisitLinks,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum QuestDuration {
+    Weekly,
+    Monthly,
+}
+
+impl QuestDuration {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QuestDuration::Weekly => "Weekly",
+            QuestDuration::Monthly => "Monthly",
+        }
+    }
+
+    fn slots(&self) -> usize {
+        match self {
+            QuestDuration::Weekly => WEEKLY_SLOTS,
+            QuestDuration::Monthly => MONTHLY_SLOTS,
+        }
+    }
+
+    /// The player's current period, e.g. "2025-W11" or "2025-03", and the
+    /// unix time of the local midnight that ends it
+    pub fn period(&self, now: i64, utc_offset_minutes: i32) -> (String, i64) {
+        let offset = utc_offset_minutes as i64 * 60;
+        let today = DateTime::from_timestamp(now + offset, 0)
+            .unwrap_or_default()
+            .date_naive();
+
+        let (period, next_start) = match self {
+            QuestDuration::Weekly => {
+                let week = today.iso_week();
+                let monday = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+                (
+                    format!("{}-W{:02}", week.year(), week.week()),
+                    monday + Duration::days(7),
+                )
+            }
+            QuestDuration::Monthly => {
+                let (year, month) = if today.month() == 12 {
+                    (today.year() + 1, 1)
+                } else {
+                    (today.year(), today.month() + 1)
+                };
+                (
+                    today.format("%Y-%m").to_string(),
+                    NaiveDate::from_ymd_opt(year, month, 1).unwrap_or(today),
+                )
+            }
+        };
+
+        let ends_at = next_start
+            .and_hms_opt(0, 0, 0)
+            .map_or(now, |midnight| midnight.and_utc().timestamp() - offset);
+        (period, ends_at)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct QuestReward {
+    #[serde(default)]
+    pub akai_balance: usize,
+    #[serde(default)]
+    pub social_score: usize,
+    #[serde(default)]
+    pub iq: usize,
+    // Added to the inventory
+    #[serde(default)]
+    pub aliens: usize,
+    #[serde(default)]
+    pub power_ups: Vec<PowerUpKind>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct QuestStep {
+    pub objective: Objective,
+    pub target: usize,
+    pub reward: QuestReward,
+    pub description: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct QuestChain {
+    pub id: String,
+    pub title: String,
+    pub duration: QuestDuration,
+    pub steps: Vec<QuestStep>,
+    pub enabled: bool,
+}
+
+/// A chain dealt to a player for one period. The steps are copied so editing
+/// the chain doesn't move the goalposts for players already on it.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct QuestSlot {
+    pub chain_id: String,
+    pub title: String,
+    pub duration: QuestDuration,
+    pub period: String,
+    pub expires_at: i64,
+    pub steps: Vec<QuestStep>,
+    // Index of the step in progress, `steps.len()` once the chain is done
+    pub step: usize,
+    pub progress: usize,
+    pub completed: bool,
+}
+
+impl QuestSlot {
+    fn new(chain: &QuestChain, period: String, expires_at: i64) -> Self {
+        QuestSlot {
+            chain_id: chain.id.clone(),
+            title: chain.title.clone(),
+            duration: chain.duration,
+            period,
+            expires_at,
+            steps: chain.steps.clone(),
+            step: 0,
+            progress: 0,
+            completed: false,
+        }
+    }
+}
+
+// D1 hands booleans back as 0 / 1
+fn bool_from_int<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<bool, D::Error> {
+    Ok(f64::deserialize(deserializer)? != 0.0)
+}
+
+#[derive(Deserialize)]
+struct QuestChainRow {
+    id: String,
+    title: String,
+    duration: String,
+    steps: String,
+    #[serde(deserialize_with = "bool_from_int")]
+    enabled: bool,
+}
+
+impl QuestChainRow {
+    // A row that no longer parses is left out rather than failing the list
+    fn into_chain(self) -> Option<QuestChain> {
+        Some(QuestChain {
+            duration: serde_json::from_value(serde_json::Value::String(self.duration)).ok()?,
+            steps: serde_json::from_str(&self.steps).ok()?,
+            id: self.id,
+            title: self.title,
+            enabled: self.enabled,
+        })
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Body of the admin create / update quest route; the id comes from the path
+#[derive(Clone, Debug, Deserialize)]
+pub struct QuestChainInput {
+    pub title: String,
+    pub duration: QuestDuration,
+    pub steps: Vec<QuestStep>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+impl QuestChainInput {
+    pub fn validate(&self) -> std::result::Result<(), &'static str> {
+        if self.title.trim().is_empty() {
+            return Err("title must not be empty");
+        }
+        if self.steps.is_empty() || self.steps.len() > MAX_QUEST_STEPS {
+            return Err("a quest needs between 1 and 10 steps");
+        }
+        if self.steps.iter().any(|step| step.target == 0) {
+            return Err("every step needs a target above 0");
+        }
+        if self
+            .steps
+            .iter()
+            .any(|step| step.description.trim().is_empty())
+        {
+            return Err("every step needs a description");
+        }
+        Ok(())
+    }
+}
+
+pub fn validate_quest_id(id: &str) -> std::result::Result<(), &'static str> {
+    if id.is_empty() || id.len() > MAX_QUEST_ID_LEN {
+        return Err("quest id must be 1-64 characters");
+    }
+    if !id
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+        return Err("quest id may only contain a-z, 0-9, '-' and '_'");
+    }
+    Ok(())
+}
+
+fn step(objective: Objective, target: usize, akai_balance: usize, description: &str) -> QuestStep {
+    QuestStep {
+        objective,
+        target,
+        reward: QuestReward {
+            akai_balance,
+            ..QuestReward::default()
+        },
+        description: description.to_string(),
+    }
+}
+
+/// Used until an admin adds enabled chains of that duration to the table
+pub fn default_chains() -> Vec<QuestChain> {
+    vec![
+        QuestChain {
+            id: "weekly-merger".to_string(),
+            title: "Merge Master".to_string(),
+            duration: QuestDuration::Weekly,
+            steps: vec![
+                step(Objective::MergeAliens, 20, 20, "Merge 20 aliens"),
+                step(Objective::MergeAliens, 50, 40, "Merge 50 aliens"),
+                QuestStep {
+                    objective: Objective::MergeAliens,
+                    target: 100,
+                    reward: QuestReward {
+                        akai_balance: 60,
+                        power_ups: vec![PowerUpKind::NearestSquarePowerUp],
+                        ..QuestReward::default()
+                    },
+                    description: "Merge 100 aliens".to_string(),
+                },
+            ],
+            enabled: true,
+        },
+        QuestChain {
+            id: "weekly-annotator".to_string(),
+            title: "Sharp Eyes".to_string(),
+            duration: QuestDuration::Weekly,
+            steps: vec![
+                step(Objective::LabelDatapoints, 10, 20, "Label 10 videos"),
+                QuestStep {
+                    objective: Objective::LabelDatapoints,
+                    target: 30,
+                    reward: QuestReward {
+                        akai_balance: 40,
+                        iq: 5,
+                        ..QuestReward::default()
+                    },
+                    description: "Label 30 videos".to_string(),
+                },
+            ],
+            enabled: true,
+        },
+        QuestChain {
+            id: "weekly-powerups".to_string(),
+            title: "Power Player".to_string(),
+            duration: QuestDuration::Weekly,
+            steps: vec![
+                step(Objective::UsePowerUps, 5, 15, "Use 5 power-ups"),
+                QuestStep {
+                    objective: Objective::UsePowerUps,
+                    target: 15,
+                    reward: QuestReward {
+                        akai_balance: 15,
+                        aliens: 5,
+                        ..QuestReward::default()
+                    },
+                    description: "Use 15 power-ups".to_string(),
+                },
+            ],
+            enabled: true,
+        },
+        QuestChain {
+            id: "weekly-social".to_string(),
+            title: "Spread the Word".to_string(),
+            duration: QuestDuration::Weekly,
+            steps: vec![
+                step(Objective::VisitLinks, 5, 15, "Visit 5 daily links"),
+                QuestStep {
+                    objective: Objective::CompleteDailyTasks,
+                    target: 15,
+                    reward: QuestReward {
+                        akai_balance: 30,
+                        social_score: 10,
+                        ..QuestReward::default()
+                    },
+                    description: "Complete 15 daily tasks".to_string(),
+                },
+            ],
+            enabled: true,
+        },
+        QuestChain {
+            id: "monthly-streak".to_string(),
+            title: "Regular".to_string(),
+            duration: QuestDuration::Monthly,
+            steps: vec![
+                step(
+                    Objective::ExtendStreak,
+                    7,
+                    50,
+                    "Count 7 days towards your streak",
+                ),
+                QuestStep {
+                    objective: Objective::ExtendStreak,
+                    target: 20,
+                    reward: QuestReward {
+                        akai_balance: 150,
+                        social_score: 25,
+                        ..QuestReward::default()
+                    },
+                    description: "Count 20 days towards your streak".to_string(),
+                },
+            ],
+            enabled: true,
+        },
+        QuestChain {
+            id: "monthly-labeler".to_string(),
+            title: "Data Hero".to_string(),
+            duration: QuestDuration::Monthly,
+            steps: vec![
+                step(Objective::LabelDatapoints, 50, 50, "Label 50 videos"),
+                step(Objective::LabelDatapoints, 150, 100, "Label 150 videos"),
+                QuestStep {
+                    objective: Objective::LabelDatapoints,
+                    target: 300,
+                    reward: QuestReward {
+                        akai_balance: 200,
+                        iq: 20,
+                        power_ups: vec![PowerUpKind::RowPowerUp, PowerUpKind::ColumnPowerUp],
+                        ..QuestReward::default()
+                    },
+                    description: "Label 300 videos".to_string(),
+                },
+            ],
+            enabled: true,
+        },
+        QuestChain {
+            id: "monthly-daily".to_string(),
+            title: "Every Day Counts".to_string(),
+            duration: QuestDuration::Monthly,
+            steps: vec![
+                step(
+                    Objective::CompleteDailyTasks,
+                    40,
+                    60,
+                    "Complete 40 daily tasks",
+                ),
+                step(
+                    Objective::CompleteDailyTasks,
+                    80,
+                    120,
+                    "Complete 80 daily tasks",
+                ),
+            ],
+            enabled: true,
+        },
+    ]
+}
+
+const QUEST_COLUMNS: &str = "id, title, duration, steps, enabled";
+
+// How long an isolate reuses the enabled chains before asking D1 again
+const QUEST_CACHE_TTL_SECS: i64 = 60;
+
+struct QuestCache {
+    fetched_at: i64,
+    chains: Vec<QuestChain>,
+}
+
+static QUEST_CACHE: Mutex<Option<QuestCache>> = Mutex::new(None);
+
+/// Drop this isolate's cached chains (other isolates catch up within the TTL)
+pub fn invalidate_quest_cache() {
+    if let Ok(mut cache) = QUEST_CACHE.lock() {
+        *cache = None;
+    }
+}
+
+async fn enabled_chains(d1: &D1Database) -> Result<Vec<QuestChain>> {
+    let now = Date::now().as_millis() as i64 / 1000;
+    if let Ok(cache) = QUEST_CACHE.lock() {
+        if let Some(cache) = cache.as_ref() {
+            if now - cache.fetched_at < QUEST_CACHE_TTL_SECS {
+                return Ok(cache.chains.clone());
+            }
+        }
+    }
+
+    let chains: Vec<QuestChain> = d1
+        .prepare(format!(
+            "SELECT {} FROM quest_chains WHERE enabled = 1",
+            QUEST_COLUMNS
+        ))
+        .all()
+        .await?
+        .results::<QuestChainRow>()?
+        .into_iter()
+        .filter_map(QuestChainRow::into_chain)
+        .collect();
+
+    if let Ok(mut cache) = QUEST_CACHE.lock() {
+        *cache = Some(QuestCache {
+            fetched_at: now,
+            chains: chains.clone(),
+        });
+    }
+    Ok(chains)
+}
+
+pub async fn list_chains(d1: &D1Database) -> Result<Vec<QuestChain>> {
+    Ok(d1
+        .prepare(format!(
+            "SELECT {} FROM quest_chains ORDER BY id",
+            QUEST_COLUMNS
+        ))
+        .all()
+        .await?
+        .results::<QuestChainRow>()?
+        .into_iter()
+        .filter_map(QuestChainRow::into_chain)
+        .collect())
+}
+
+/// Create or overwrite the chain `id`
+pub async fn upsert_chain(
+    d1: &D1Database,
+    id: &str,
+    input: &QuestChainInput,
+) -> Result<QuestChain> {
+    d1.prepare(
+        "INSERT INTO quest_chains (id, title, duration, steps, enabled, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(id) DO UPDATE SET
+            title = ?2, duration = ?3, steps = ?4, enabled = ?5, updated_at = ?6",
+    )
+    .bind(&[
+        id.into(),
+        input.title.as_str().into(),
+        input.duration.as_str().into(),
+        serde_json::to_string(&input.steps)?.into(),
+        (input.enabled as u8).into(),
+        ((Date::now().as_millis() / 1000) as f64).into(),
+    ])?
+    .run()
+    .await?;
+
+    invalidate_quest_cache();
+    Ok(QuestChain {
+        id: id.to_string(),
+        title: input.title.clone(),
+        duration: input.duration,
+        steps: input.steps.clone(),
+        enabled: input.enabled,
+    })
+}
+
+pub async fn delete_chain(d1: &D1Database, id: &str) -> Result<bool> {
+    let removed = d1
+        .prepare("DELETE FROM quest_chains WHERE id = ?")
+        .bind(&[id.into()])?
+        .run()
+        .await?
+        .meta()?
+        .and_then(|m| m.changes)
+        .unwrap_or(0);
+
+    invalidate_quest_cache();
+    Ok(removed > 0)
+}
+
+/// Drop quests whose period is over and deal new chains into the free
+/// slots. Chains already held this period are not dealt again.
+pub async fn refresh_quests(user_data: &mut UserData, d1: &D1Database, now: i64) -> Result<()> {
+    user_data.quests.retain(|slot| now < slot.expires_at);
+
+    let mut chains: Vec<QuestChain> = enabled_chains(d1).await?;
+    let offset = user_data.profile.utc_offset_minutes;
+    let mut rng = rand::thread_rng();
+
+    for duration in [QuestDuration::Weekly, QuestDuration::Monthly] {
+        let free = duration.slots().saturating_sub(
+            user_data
+                .quests
+                .iter()
+                .filter(|slot| slot.duration == duration)
+                .count(),
+        );
+        if free == 0 {
+            continue;
+        }
+
+        if !chains.iter().any(|chain| chain.duration == duration) {
+            chains.extend(
+                default_chains()
+                    .into_iter()
+                    .filter(|chain| chain.duration == duration),
+            );
+        }
+        let candidates: Vec<&QuestChain> = chains
+            .iter()
+            .filter(|chain| chain.duration == duration && !chain.steps.is_empty())
+            .filter(|chain| !user_data.quests.iter().any(|s| s.chain_id == chain.id))
+            .collect();
+
+        let (period, expires_at) = duration.period(now, offset);
+        let dealt: Vec<QuestSlot> = candidates
+            .choose_multiple(&mut rng, free)
+            .map(|chain| QuestSlot::new(chain, period.clone(), expires_at))
+            .collect();
+        user_data.quests.extend(dealt);
+    }
+
+    Ok(())
+}
+
+/// Record an event: count it towards the daily tasks and the streak, then
+/// towards every active quest step it (or the daily progress it made) matches
+pub fn emit(user_data: &mut UserData, event: GameEvent) {
+    let completed_before = user_data.daily.total_completed;
+    let streak_day_before = user_data.progress.streak_last_day.clone();
+
+    let counts_for_quests = match event {
+        GameEvent::AlienMerged => {
+            advance_daily_counter(user_data, DailyCounter::Merge);
+            user_data.daily.daily_merge.0 <= DAILY_QUEST_MERGES
+        }
+        GameEvent::PowerUpUsed => {
+            advance_daily_counter(user_data, DailyCounter::PowerUps);
+            user_data.daily.daily_powerups.0 <= DAILY_QUEST_POWER_UPS
+        }
+        GameEvent::DatapointLabeled => {
+            advance_daily_counter(user_data, DailyCounter::Annotate);
+            true
+        }
+        GameEvent::LinkVisited => {
+            user_data.daily.total_completed += 1;
+            streak::record_completion(user_data);
+            true
+        }
+    };
+
+    if counts_for_quests {
+        advance_quests(user_data, event.objective(), 1);
+    }
+    let daily_completed = user_data
+        .daily
+        .total_completed
+        .saturating_sub(completed_before);
+    advance_quests(user_data, Objective::CompleteDailyTasks, daily_completed);
+    if user_data.progress.streak_last_day != streak_day_before {
+        advance_quests(user_data, Objective::ExtendStreak, 1);
+    }
+}
+
+fn advance_quests(user_data: &mut UserData, objective: Objective, amount: usize) {
+    if amount == 0 {
+        return;
+    }
+    let now = Date::now().as_millis() as i64 / 1000;
+
+    let mut finished = Vec::new();
+    for slot in user_data.quests.iter_mut() {
+        if slot.completed || now >= slot.expires_at {
+            continue;
+        }
+        let Some(step) = slot.steps.get(slot.step) else {
+            continue;
+        };
+        if step.objective != objective {
+            continue;
+        }
+
+        slot.progress += amount;
+        if slot.progress >= step.target {
+            finished.push((
+                slot.title.clone(),
+                slot.chain_id.clone(),
+                slot.step,
+                step.clone(),
+            ));
+            slot.step += 1;
+            slot.progress = 0;
+            slot.completed = slot.step >= slot.steps.len();
+        }
+    }
+
+    for (title, chain_id, index, step) in finished {
+        pay_step(user_data, &title, &chain_id, index, &step);
+    }
+}
+
+fn pay_step(user_data: &mut UserData, title: &str, chain_id: &str, index: usize, step: &QuestStep) {
+    let reward = &step.reward;
+    let applied = RewardPayload {
+        akai_balance: reward.akai_balance as i64,
+        iq: reward.iq as i64,
+        social_score: reward.social_score as i64,
+    }
+    .apply(&mut user_data.progress);
+    user_data.game_state.inventory_aliens += reward.aliens;
+    user_data
+        .game_state
+        .power_ups
+        .extend(reward.power_ups.iter().copied());
+    calculate_product(user_data);
+
+    let mut metadata = HashMap::new();
+    applied.write_metadata(&mut metadata);
+    metadata.insert("quest_id".to_string(), chain_id.to_string());
+    metadata.insert("step".to_string(), index.to_string());
+    metadata.insert("aliens".to_string(), reward.aliens.to_string());
+    metadata.insert(
+        "power_ups".to_string(),
+        serde_json::to_string(&reward.power_ups).unwrap_or_default(),
+    );
+
+    let notification = new_notification(
+        &user_data.profile.user_id,
+        NotificationType::System,
+        &format!("Quest \"{}\": {} done!", title, step.description),
+        Some(metadata),
+    );
+    user_data.notifications.push(notification);
+}
@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use wasm_bindgen::JsValue;
+use worker::*;
+
+use crate::types::{Op, WsError};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub capacity: f64,
+    pub refill_per_sec: f64,
+}
+
+impl RateLimit {
+    const fn new(capacity: f64, refill_per_sec: f64) -> Self {
+        Self {
+            capacity,
+            refill_per_sec,
+        }
+    }
+}
+
+// Per client IP, keyed by route
+const DEFAULT_ROUTE_LIMITS: &[(&str, RateLimit)] = &[
+    ("register", RateLimit::new(5.0, 1.0 / 60.0)),
+    ("login", RateLimit::new(10.0, 1.0 / 10.0)),
+    ("transcribe", RateLimit::new(5.0, 1.0 / 30.0)),
+    ("password_reset", RateLimit::new(3.0, 1.0 / 300.0)),
+];
+
+// Per user, keyed by Op variant name. Anything not listed uses DEFAULT_OP_LIMIT.
+const DEFAULT_OP_LIMITS: &[(&str, RateLimit)] = &[
+    ("GenerateDailyTasks", RateLimit::new(3.0, 1.0 / 60.0)),
+    ("SubmitVideoLabel", RateLimit::new(10.0, 1.0)),
+    ("UseReferralCode", RateLimit::new(3.0, 1.0 / 60.0)),
+    ("ClaimVanityCode", RateLimit::new(3.0, 1.0 / 60.0)),
+    ("UpdateUserName", RateLimit::new(3.0, 1.0 / 60.0)),
+    ("UpdateEmail", RateLimit::new(3.0, 1.0 / 300.0)),
+    ("UpdatePassword", RateLimit::new(3.0, 1.0 / 60.0)),
+    ("SendFriendRequest", RateLimit::new(10.0, 1.0 / 10.0)),
+    ("ExportMyData", RateLimit::new(2.0, 1.0 / 300.0)),
+    ("DeleteAccount", RateLimit::new(3.0, 1.0 / 60.0)),
+];
+
+const DEFAULT_OP_LIMIT: RateLimit = RateLimit::new(30.0, 10.0);
+
+/// Optional overrides from the RATE_LIMITS var, e.g.
+/// `{"routes": {"register": {"capacity": 2, "refill_per_sec": 0.01}}, "ops": {"default": {...}}}`
+#[derive(Deserialize, Default)]
+struct RateLimitOverrides {
+    #[serde(default)]
+    routes: HashMap<String, RateLimit>,
+    #[serde(default)]
+    ops: HashMap<String, RateLimit>,
+}
+
+fn overrides(env: &Env) -> RateLimitOverrides {
+    env.var("RATE_LIMITS")
+        .ok()
+        .and_then(|v| serde_json::from_str(&v.to_string()).ok())
+        .unwrap_or_default()
+}
+
+pub fn route_limit(env: &Env, route: &str) -> Option<RateLimit> {
+    overrides(env).routes.get(route).copied().or_else(|| {
+        DEFAULT_ROUTE_LIMITS
+            .iter()
+            .find(|(name, _)| *name == route)
+            .map(|(_, limit)| *limit)
+    })
+}
+
+pub fn op_limit(env: &Env, op_kind: &str) -> RateLimit {
+    let overrides = overrides(env);
+    overrides
+        .ops
+        .get(op_kind)
+        .copied()
+        .or_else(|| {
+            DEFAULT_OP_LIMITS
+                .iter()
+                .find(|(name, _)| *name == op_kind)
+                .map(|(_, limit)| *limit)
+        })
+        .or_else(|| overrides.ops.get("default").copied())
+        .unwrap_or(DEFAULT_OP_LIMIT)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    last_refill_ms: f64,
+}
+
+impl TokenBucket {
+    pub fn full(limit: &RateLimit, now_ms: f64) -> Self {
+        Self {
+            tokens: limit.capacity,
+            last_refill_ms: now_ms,
+        }
+    }
+
+    /// Take one token, or return how many seconds until one is available
+    pub fn try_take(&mut self, limit: &RateLimit, now_ms: f64) -> std::result::Result<(), u64> {
+        let elapsed_secs = ((now_ms - self.last_refill_ms) / 1000.0).max(0.0);
+        self.tokens = (self.tokens + elapsed_secs * limit.refill_per_sec).min(limit.capacity);
+        self.last_refill_ms = now_ms;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if limit.refill_per_sec > 0.0 {
+            Err(((1.0 - self.tokens) / limit.refill_per_sec).ceil() as u64)
+        } else {
+            Err(u64::MAX)
+        }
+    }
+}
+
+/// Take a token from `buckets[key]`, creating a full bucket on first use
+pub fn take_from(
+    buckets: &mut HashMap<String, TokenBucket>,
+    key: &str,
+    limit: &RateLimit,
+) -> std::result::Result<(), u64> {
+    let now_ms = Date::now().as_millis() as f64;
+    buckets
+        .entry(key.to_string())
+        .or_insert_with(|| TokenBucket::full(limit, now_ms))
+        .try_take(limit, now_ms)
+}
+
+pub fn rate_limited_response(scope: &str, retry_after_secs: u64) -> Result<Response> {
+    let body = WsError {
+        error: "RateLimited".to_string(),
+        code: 429,
+        message: format!("Too many {} requests", scope),
+        retry_after_secs: Some(retry_after_secs),
+    };
+    let mut headers = Headers::new();
+    headers.set("Retry-After", &retry_after_secs.to_string())?;
+    Ok(Response::from_json(&body)?
+        .with_status(429)
+        .with_headers(headers))
+}
+
+/// Per-op limit applied inside the user's DO, so a flood on one socket only
+/// throttles that player
+pub fn check_op(
+    env: &Env,
+    buckets: &mut HashMap<String, TokenBucket>,
+    op: &Op,
+) -> Option<Result<Response>> {
+    if op.is_internal() {
+        return None;
+    }
+    let kind = op.kind();
+    take_from(buckets, &kind, &op_limit(env, &kind))
+        .err()
+        .map(|retry_after| rate_limited_response(&kind, retry_after))
+}
+
+pub fn client_ip(req: &Request) -> String {
+    req.headers()
+        .get("CF-Connecting-IP")
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+#[derive(Serialize, Deserialize)]
+struct TakeRequest {
+    limit: RateLimit,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TakeResponse {
+    allowed: bool,
+    retry_after_secs: u64,
+}
+
+/// Per-IP limit for a worker route, held in the RateLimiter DO named after the
+/// route and IP. Returns the 429 response to send back when the caller is over.
+/// Fails open: a broken limiter should not take the API down with it.
+pub async fn check_route(
+    env: &Env,
+    route: &str,
+    client_key: &str,
+    rid: &str,
+) -> Option<Result<Response>> {
+    let limit = route_limit(env, route)?;
+
+    let result: Result<TakeResponse> = async {
+        let namespace = env.durable_object("RATE_LIMITER")?;
+        let stub = namespace
+            .id_from_name(&format!("{}:{}", route, client_key))?
+            .get_stub()?;
+
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post);
+        init.with_body(Some(JsValue::from_str(&serde_json::to_string(
+            &TakeRequest { limit },
+        )?)));
+
+        stub.fetch_with_request(Request::new_with_init("https://rate-limiter/", &init)?)
+            .await?
+            .json()
+            .await
+    }
+    .await;
+
+    match result {
+        Ok(TakeResponse { allowed: true, .. }) => None,
+        Ok(TakeResponse {
+            retry_after_secs, ..
+        }) => {
+            log_info!(rid, "rate_limit.rejected", "route" => route, "retry_after_secs" => retry_after_secs);
+            Some(rate_limited_response(route, retry_after_secs))
+        }
+        Err(e) => {
+            log_error!(rid, "rate_limit.unavailable", "route" => route, "error" => e.to_string());
+            None
+        }
+    }
+}
+
+/// One token bucket per DO instance; instances are named "<route>:<client>"
+#[durable_object]
+pub struct RateLimiter {
+    state: State,
+    bucket: Option<TokenBucket>,
+}
+
+#[durable_object]
+impl DurableObject for RateLimiter {
+    fn new(state: State, _env: Env) -> Self {
+        Self {
+            state,
+            bucket: None,
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let TakeRequest { limit } = match req.json().await {
+            Ok(body) => body,
+            Err(_) => return Response::error("Invalid request format", 400),
+        };
+
+        let now_ms = Date::now().as_millis() as f64;
+        if self.bucket.is_none() {
+            self.bucket = self.state.storage().get("bucket").await.ok();
+        }
+        let bucket = self
+            .bucket
+            .get_or_insert_with(|| TokenBucket::full(&limit, now_ms));
+
+        let response = match bucket.try_take(&limit, now_ms) {
+            Ok(()) => TakeResponse {
+                allowed: true,
+                retry_after_secs: 0,
+            },
+            Err(retry_after_secs) => TakeResponse {
+                allowed: false,
+                retry_after_secs,
+            },
+        };
+
+        // Persisted so an evicted limiter doesn't hand out a fresh bucket
+        let bucket = bucket.clone();
+        self.state.storage().put("bucket", bucket).await?;
+
+        Response::from_json(&response)
+    }
+}
@@ -0,0 +1,387 @@
+use chrono::Utc;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use worker::{D1Database, Env, Result};
+
+use crate::notification::{new_notification, send_notification, NotificationType};
+use crate::types::UserData;
+use crate::utils::is_registered;
+
+// What the referrer gets once the referral is credited
+pub const REFERRER_AKAI_REWARD: usize = 25;
+pub const REFERRER_SOCIAL_REWARD: usize = 10;
+
+// Share of the direct reward paid to the referrer's own referrer
+pub const SECOND_TIER_PERCENT: usize = 20;
+
+// What the new player gets for redeeming a code
+pub const REFEREE_AKAI_REWARD: usize = 25;
+pub const REFEREE_SOCIAL_REWARD: usize = 5;
+
+// Attempts at drawing a random code before registration gives up
+const CODE_GENERATION_ATTEMPTS: usize = 5;
+
+pub fn random_referral_code() -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(8)
+        .map(|b| b as char)
+        .collect()
+}
+
+/// Whether any player other than `user_id` owns `code` (case-insensitively)
+pub async fn referral_code_taken(d1: &D1Database, code: &str, user_id: &str) -> Result<bool> {
+    let row = d1
+        .prepare(
+            "SELECT 1 AS taken FROM social_data WHERE referal_code = ? COLLATE NOCASE AND user_id != ?",
+        )
+        .bind(&[code.into(), user_id.into()])?
+        .first::<usize>(Some("taken"))
+        .await?;
+
+    Ok(row.is_some())
+}
+
+/// Draw random codes until one is free, retrying on collision
+pub async fn generate_unique_referral_code(d1: &D1Database, user_id: &str) -> Result<String> {
+    for _ in 0..CODE_GENERATION_ATTEMPTS {
+        let code = random_referral_code();
+        if !referral_code_taken(d1, &code, user_id).await? {
+            return Ok(code);
+        }
+    }
+
+    Err(worker::Error::RustError(
+        "Could not generate a unique referral code".to_string(),
+    ))
+}
+
+/// Persist a new code straight away so it can be redeemed before the next sync.
+/// The unique index on social_data.referal_code rejects a code claimed concurrently.
+pub async fn set_referral_code(d1: &D1Database, user_id: &str, code: &str) -> Result<()> {
+    d1.prepare("UPDATE social_data SET referal_code = ? WHERE user_id = ?")
+        .bind(&[code.into(), user_id.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize)]
+pub struct ReferralRedemption {
+    pub referee_id: String,
+    pub referrer_id: String,
+    pub code: String,
+    pub redeemed_at: i64,
+    pub credited_at: Option<i64>,
+}
+
+/// The redemption made by `referee_id`, if they have ever used a code
+pub async fn get_redemption(
+    d1: &D1Database,
+    referee_id: &str,
+) -> Result<Option<ReferralRedemption>> {
+    d1.prepare(
+        "SELECT referee_id, referrer_id, code, redeemed_at, credited_at
+         FROM referral_redemptions WHERE referee_id = ?",
+    )
+    .bind(&[referee_id.into()])?
+    .first::<ReferralRedemption>(None)
+    .await
+}
+
+/// Record a redemption, not yet credited. The primary key on referee_id makes
+/// this fail if the player already redeemed a code, even when two requests race.
+pub async fn record_redemption(
+    d1: &D1Database,
+    referee_id: &str,
+    referrer_id: &str,
+    code: &str,
+) -> Result<()> {
+    d1.prepare(
+        "INSERT INTO referral_redemptions (referee_id, referrer_id, code, redeemed_at, credited_at)
+         VALUES (?, ?, ?, ?, NULL)",
+    )
+    .bind(&[
+        referee_id.into(),
+        referrer_id.into(),
+        code.into(),
+        (Utc::now().timestamp() as f64).into(),
+    ])?
+    .run()
+    .await?;
+
+    Ok(())
+}
+
+/// Undo a redemption whose referrer couldn't be paid, so the code can be tried again
+pub async fn delete_redemption(d1: &D1Database, referee_id: &str) -> Result<()> {
+    d1.prepare("DELETE FROM referral_redemptions WHERE referee_id = ? AND credited_at IS NULL")
+        .bind(&[referee_id.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+pub async fn mark_credited(d1: &D1Database, referee_id: &str) -> Result<()> {
+    d1.prepare("UPDATE referral_redemptions SET credited_at = ? WHERE referee_id = ?")
+        .bind(&[(Utc::now().timestamp() as f64).into(), referee_id.into()])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// King level the referee has to reach before the referrer is credited.
+/// `None` (unset or 0) credits the referrer immediately.
+pub fn referral_milestone(env: &Env) -> Option<usize> {
+    env.var("REFERRAL_MILESTONE_KING_LVL")
+        .ok()
+        .and_then(|v| v.to_string().parse::<usize>().ok())
+        .filter(|lvl| *lvl > 0)
+}
+
+pub fn milestone_reached(user_data: &UserData, milestone: Option<usize>) -> bool {
+    milestone.is_none_or(|lvl| user_data.game_state.king_lvl >= lvl)
+}
+
+/// Send the referrer their reward via a Referral notification on their DO, then
+/// pay the second tier (the referrer's own referrer) a fraction of it
+pub async fn credit_referrer(
+    env: &Env,
+    d1: &D1Database,
+    referrer_id: &str,
+    referee_id: &str,
+    rid: &str,
+) -> Result<()> {
+    pay_referral_reward(
+        env,
+        d1,
+        referrer_id,
+        referee_id,
+        1,
+        REFERRER_AKAI_REWARD,
+        REFERRER_SOCIAL_REWARD,
+    )
+    .await?;
+
+    // A failed second-tier payout must not undo the direct one
+    match get_redemption(d1, referrer_id).await {
+        Ok(Some(parent)) if parent.referrer_id != referee_id => {
+            if let Err(e) = pay_referral_reward(
+                env,
+                d1,
+                &parent.referrer_id,
+                referee_id,
+                2,
+                REFERRER_AKAI_REWARD * SECOND_TIER_PERCENT / 100,
+                REFERRER_SOCIAL_REWARD * SECOND_TIER_PERCENT / 100,
+            )
+            .await
+            {
+                log_error!(rid, "referral.second_tier_failed", "referrer_id" => parent.referrer_id, "referee_id" => referee_id, "error" => e.to_string());
+            }
+        }
+        Ok(_) => {}
+        Err(e) => {
+            log_error!(rid, "referral.second_tier_lookup_failed", "referee_id" => referee_id, "error" => e.to_string())
+        }
+    }
+
+    Ok(())
+}
+
+/// Idempotency key of the tier `tier` reward earned from `referee_id`
+fn referral_reward_key(referee_id: &str, tier: usize) -> String {
+    format!("referral:{}:{}", referee_id, tier)
+}
+
+/// Pay one referral reward. The ledger row is written first and skips rewards
+/// already paid; it is removed again if the push fails so a retry pays it,
+/// and the reward key stops the DO applying a push that did land twice.
+async fn pay_referral_reward(
+    env: &Env,
+    d1: &D1Database,
+    beneficiary_id: &str,
+    referee_id: &str,
+    tier: usize,
+    akai: usize,
+    social_score: usize,
+) -> Result<()> {
+    let already_paid = d1
+        .prepare("SELECT 1 AS paid FROM referral_rewards WHERE referee_id = ? AND tier = ?")
+        .bind(&[referee_id.into(), tier.into()])?
+        .first::<usize>(Some("paid"))
+        .await?
+        .is_some();
+    if already_paid {
+        return Ok(());
+    }
+
+    let reward_id = d1
+        .prepare(
+            "INSERT INTO referral_rewards (beneficiary_id, referee_id, tier, akai, social_score, created_at)
+             VALUES (?, ?, ?, ?, ?, ?)
+             RETURNING reward_id",
+        )
+        .bind(&[
+            beneficiary_id.into(),
+            referee_id.into(),
+            tier.into(),
+            akai.into(),
+            social_score.into(),
+            (Utc::now().timestamp() as f64).into(),
+        ])?
+        .first::<f64>(Some("reward_id"))
+        .await?;
+
+    let mut metadata = HashMap::new();
+    metadata.insert("used_by".to_string(), referee_id.to_string());
+    metadata.insert("tier".to_string(), tier.to_string());
+    metadata.insert("social_score".to_string(), social_score.to_string());
+    metadata.insert("akai_balance".to_string(), akai.to_string());
+
+    let message = if tier == 1 {
+        "Your referral code was used!"
+    } else {
+        "Someone you referred brought in a new player!"
+    };
+
+    let mut notification = new_notification(
+        beneficiary_id,
+        NotificationType::Referral,
+        message,
+        Some(metadata),
+    );
+    notification.reward_key = Some(referral_reward_key(referee_id, tier));
+
+    if let Err(e) = send_notification(env, &notification).await {
+        if let Some(reward_id) = reward_id {
+            d1.prepare("DELETE FROM referral_rewards WHERE reward_id = ?")
+                .bind(&[reward_id.into()])?
+                .run()
+                .await?;
+        }
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Credit a referral that was waiting on the referee's milestone, once it is reached
+pub async fn credit_pending_referral(
+    user_data: &mut UserData,
+    d1: &D1Database,
+    env: &Env,
+    rid: &str,
+) {
+    let Some(referrer_id) = user_data.social.pending_referrer.clone() else {
+        return;
+    };
+
+    if !milestone_reached(user_data, referral_milestone(env)) {
+        return;
+    }
+
+    // A referrer who deleted their account can't be paid any more
+    if !is_registered(d1, &referrer_id).await {
+        log_info!(rid, "referral.referrer_gone", "referrer_id" => referrer_id, "referee_id" => user_data.profile.user_id);
+        user_data.social.pending_referrer = None;
+        return;
+    }
+
+    let referee_id = user_data.profile.user_id.clone();
+    if let Err(e) = credit_referrer(env, d1, &referrer_id, &referee_id, rid).await {
+        log_error!(rid, "referral.credit_failed", "referrer_id" => referrer_id, "referee_id" => referee_id, "error" => e.to_string());
+        return;
+    }
+    if let Err(e) = mark_credited(d1, &referee_id).await {
+        log_error!(rid, "referral.mark_credited_failed", "referee_id" => referee_id, "error" => e.to_string());
+    }
+
+    log_info!(rid, "referral.credited", "referrer_id" => referrer_id, "referee_id" => referee_id);
+    user_data.social.pending_referrer = None;
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize)]
+pub struct ReferralEntry {
+    pub user_id: String,
+    pub user_name: Option<String>,
+    pub tier: usize,
+    pub redeemed_at: i64,
+    pub credited_at: Option<i64>,
+    pub king_lvl: usize,
+    pub iq: usize,
+    pub product: usize,
+    pub total_task_completed: usize,
+    pub akai_earned: usize,
+    pub social_earned: usize,
+}
+
+/// What `GetReferrals` answers with
+#[derive(Serialize, Debug)]
+pub struct ReferralOverview {
+    pub user_id: String,
+    pub referred_by: Option<String>,
+    pub referrals: Vec<ReferralEntry>,
+    pub akai_earned: usize,
+    pub social_earned: usize,
+}
+
+/// A player's referrer and referral tree, with the rewards it paid them
+pub async fn referral_overview(d1: &D1Database, user_id: &str) -> Result<ReferralOverview> {
+    let referred_by = get_redemption(d1, user_id).await?.map(|r| r.referrer_id);
+    let referrals = list_referrals(d1, user_id).await?;
+
+    Ok(ReferralOverview {
+        user_id: user_id.to_string(),
+        referred_by,
+        akai_earned: referrals.iter().map(|r| r.akai_earned).sum(),
+        social_earned: referrals.iter().map(|r| r.social_earned).sum(),
+        referrals,
+    })
+}
+
+/// Direct (tier 1) and second-tier referrals of a player with their progress
+/// and what the player earned from each
+pub async fn list_referrals(d1: &D1Database, user_id: &str) -> Result<Vec<ReferralEntry>> {
+    d1.prepare(
+        r#"
+        WITH tree AS (
+            SELECT referee_id, redeemed_at, credited_at, 1 AS tier
+            FROM referral_redemptions
+            WHERE referrer_id = ?1
+            UNION ALL
+            SELECT child.referee_id, child.redeemed_at, child.credited_at, 2 AS tier
+            FROM referral_redemptions child
+            JOIN referral_redemptions parent ON child.referrer_id = parent.referee_id
+            WHERE parent.referrer_id = ?1 AND child.referee_id != ?1
+        )
+        SELECT
+            tree.referee_id as "user_id",
+            user_profile.user_name as "user_name",
+            tree.tier as "tier",
+            tree.redeemed_at as "redeemed_at",
+            tree.credited_at as "credited_at",
+            COALESCE(game_state.king_lvl, 0) as "king_lvl",
+            COALESCE(progress.iq, 0) as "iq",
+            COALESCE(progress.product, 0) as "product",
+            COALESCE(progress.total_task_completed, 0) as "total_task_completed",
+            COALESCE((SELECT SUM(akai) FROM referral_rewards
+                      WHERE beneficiary_id = ?1 AND referee_id = tree.referee_id), 0) as "akai_earned",
+            COALESCE((SELECT SUM(social_score) FROM referral_rewards
+                      WHERE beneficiary_id = ?1 AND referee_id = tree.referee_id), 0) as "social_earned"
+        FROM tree
+        LEFT JOIN user_profile ON user_profile.user_id = tree.referee_id
+        LEFT JOIN game_state ON game_state.user_id = tree.referee_id
+        LEFT JOIN progress ON progress.user_id = tree.referee_id
+        ORDER BY tree.tier, tree.redeemed_at DESC
+        "#,
+    )
+    .bind(&[user_id.into()])?
+    .all()
+    .await?
+    .results::<ReferralEntry>()
+}
@@ -3,8 +3,12 @@ use wasm_bindgen::JsValue;
 use worker::*;
 
 use crate::{
-    sql::update_user_data,
+    consensus, forward_op_to_do, logging,
+    referral::generate_unique_referral_code,
+    sql::{self, login_name_taken, update_user_data},
     types::{DurableObjectAugmentedMsg, Op, UserData, WsMsg},
+    validation::USER_NAME_MAX_LEN,
+    video_pool,
 };
 
 #[event(scheduled)]
@@ -13,206 +17,234 @@ async fn cron(event: ScheduledEvent, env: Env, ctx: ScheduleContext) {
 }
 
 async fn run_cron_logic(env: Env) {
-    console_log!("Cron job logic started.");
+    logging::init(&env);
+    let rid = logging::new_request_id();
+    log_info!(&rid, "cron.started");
 
     let d1 = match env.d1("D1_DATABASE") {
         Ok(db) => db,
         Err(e) => {
-            console_error!("Failed to get D1 binding: {}", e);
+            log_error!(&rid, "cron.d1_unavailable", "error" => e.to_string());
             return;
         }
     };
 
+    if let Err(e) = sql::ensure_schema(&d1).await {
+        log_error!(&rid, "cron.schema_failed", "error" => e.to_string());
+        return;
+    }
+    if let Err(e) = build_unique_indexes(&d1, &env, &rid).await {
+        log_error!(&rid, "cron.unique_index_migration_failed", "error" => e.to_string());
+    }
+
+    if let Err(e) = video_pool::refill_pool(&d1, &env, &rid).await {
+        log_error!(&rid, "cron.video_pool_refill_failed", "error" => e.to_string());
+    }
+
+    if let Err(e) = consensus::retry_pending_deliveries(&d1, &env, &rid).await {
+        log_error!(&rid, "cron.delivery_retry_failed", "error" => e.to_string());
+    }
+
     let principals: Vec<String> = match get_all_user_ids(&d1).await {
         Ok(ids) => ids,
         Err(e) => {
-            console_error!("Failed to retrieve user IDs for cron: {}", e);
+            log_error!(&rid, "cron.user_ids_failed", "error" => e.to_string());
             return;
         }
     };
 
-    console_log!("Found {} users to process.", principals.len());
+    log_info!(&rid, "cron.users_found", "count" => principals.len());
 
     let mut futures = FuturesUnordered::new();
 
     for user_id_str in principals.iter() {
         let env_clone = env.clone();
         let user_id_clone = user_id_str.clone();
+        let rid = rid.clone();
 
         futures.push(async move {
-            let d1_for_future = match env_clone.d1("D1_DATABASE") {
-                Ok(db) => db,
-                Err(e) => {
-                    console_error!("Failed to get D1 binding for user {}: {}", user_id_clone, e);
-                    return;
+            match sync_user(&env_clone, &user_id_clone, &rid).await {
+                Ok(()) => {
+                    log_debug!(&rid, "cron.user_synced", "user_id" => user_id_clone);
+                    true
                 }
-            };
-
-            console_log!("Processing user: {}", user_id_clone);
-            let user_data_obj = match env_clone.durable_object("USER_DATA_WRAPPER") {
-                Ok(obj) => obj,
                 Err(e) => {
-                    console_error!(
-                        "Failed to get DO namespace for user {}: {}",
-                        user_id_clone,
-                        e
-                    );
-                    return;
+                    log_error!(&rid, "cron.user_sync_failed", "user_id" => user_id_clone, "error" => e.to_string());
+                    false
                 }
-            };
+            }
+        });
+    }
 
-            let user_data_id = match user_data_obj.id_from_name(&user_id_clone) {
-                Ok(id) => {
-                    console_log!("Got DO ID for user: {}", user_id_clone);
-                    id
-                }
-                Err(e) => {
-                    console_error!("Failed to get DO ID for user {}: {}", user_id_clone, e);
-                    return;
-                }
-            };
+    let mut count = 0;
+    let mut failed = 0;
+    while let Some(ok) = futures.next().await {
+        count += 1;
+        if !ok {
+            failed += 1;
+        }
+    }
 
-            let user_data_stub = match user_data_id.get_stub() {
-                Ok(stub) => {
-                    console_log!("Got stub for DO ID: {}", user_data_id);
-                    stub
-                }
-                Err(e) => {
-                    console_error!("Failed to get stub for DO ID {}: {}", user_data_id, e);
-                    return;
-                }
-            };
+    log_info!(&rid, "cron.finished", "processed" => count, "failed" => failed);
+}
 
-            let op_request = DurableObjectAugmentedMsg {
-                user_id: user_id_clone.clone(),
-                op: Op::GetData,
-            };
+// Attempts at a free "<name>_<n>" before a duplicate username is left for the next run
+const RENAME_ATTEMPTS: usize = 100;
 
-            let op_request_json = match serde_json::to_string(&op_request) {
-                Ok(json) => {
-                    console_log!("Serialized OpRequest for user: {}", user_id_clone);
-                    json
-                }
-                Err(e) => {
-                    console_error!(
-                        "Failed to serialize OpRequest for user {}: {}",
-                        user_id_clone,
-                        e
-                    );
-                    return;
-                }
-            };
+#[derive(serde::Deserialize)]
+struct DuplicateCode {
+    user_id: String,
+}
 
-            let mut request_init = RequestInit::new();
-            request_init.with_method(Method::Post);
-            request_init.with_body(Some(JsValue::from_str(&op_request_json)));
+#[derive(serde::Deserialize)]
+struct DuplicateName {
+    user_id: String,
+    user_name: String,
+}
 
-            let request_url = "https://internal-do-fetch.com/";
+/// Rename the usernames and replace the referral codes that only differ by
+/// case from an older account's, then build the unique indexes they were blocking
+async fn build_unique_indexes(d1: &D1Database, env: &Env, rid: &str) -> Result<()> {
+    if sql::create_unique_indexes(d1).await.is_ok() {
+        return Ok(());
+    }
 
-            let request = match Request::new_with_init(request_url, &request_init) {
-                Ok(req) => {
-                    console_log!("Created request for user: {}", user_id_clone);
-                    req
-                }
-                Err(e) => {
-                    console_error!("Failed to create request for user {}: {}", user_id_clone, e);
-                    return;
-                }
-            };
+    let duplicates = d1
+        .prepare(
+            "SELECT user_id, user_name FROM user_profile p
+             WHERE user_name IS NOT NULL AND EXISTS (
+                 SELECT 1 FROM user_profile o
+                 WHERE o.user_name = p.user_name COLLATE NOCASE AND o.rowid < p.rowid
+             )",
+        )
+        .all()
+        .await?
+        .results::<DuplicateName>()?;
+
+    for duplicate in duplicates {
+        let new_name = free_user_name(d1, &duplicate.user_name, &duplicate.user_id).await?;
+        // The DO writes the name to D1 too, so its next sync doesn't restore the old one
+        forward_op_to_do(
+            env,
+            &DurableObjectAugmentedMsg {
+                user_id: duplicate.user_id.clone(),
+                op: Op::RenameInternal(new_name.clone()),
+                request_id: rid.to_string(),
+            },
+        )
+        .await?;
+        log_warn!(rid, "migration.user_renamed", "user_id" => duplicate.user_id, "from" => duplicate.user_name, "to" => new_name);
+    }
 
-            let mut user_res = match user_data_stub.fetch_with_request(request).await {
-                Ok(res) => {
-                    console_log!("Fetched user data from DO for user: {}", user_id_clone);
-                    res
-                }
-                Err(e) => {
-                    console_error!(
-                        "Failed to fetch user data from DO for user {}: {}",
-                        user_id_clone,
-                        e
-                    );
-                    return;
-                }
-            };
+    let duplicates = d1
+        .prepare(
+            "SELECT user_id FROM social_data s
+             WHERE EXISTS (
+                 SELECT 1 FROM social_data o
+                 WHERE o.referal_code = s.referal_code COLLATE NOCASE AND o.rowid < s.rowid
+             )",
+        )
+        .all()
+        .await?
+        .results::<DuplicateCode>()?;
+
+    for duplicate in duplicates {
+        let code = generate_unique_referral_code(d1, &duplicate.user_id).await?;
+        forward_op_to_do(
+            env,
+            &DurableObjectAugmentedMsg {
+                user_id: duplicate.user_id.clone(),
+                op: Op::SetReferralCodeInternal(code.clone()),
+                request_id: rid.to_string(),
+            },
+        )
+        .await?;
+        log_warn!(rid, "migration.referral_code_replaced", "user_id" => duplicate.user_id, "code" => code);
+    }
 
-            let data: UserData = match user_res.json().await {
-                Ok(json) => {
-                    console_log!("Parsed JSON from DO for user: {}", user_id_clone);
-                    json
-                }
-                Err(e) => {
-                    console_error!(
-                        "Failed to parse JSON from DO for user {}: {}",
-                        user_id_clone,
-                        e
-                    );
-                    return;
-                }
-            };
+    sql::create_unique_indexes(d1).await
+}
 
-            match update_user_data(&data, &d1_for_future).await {
-                Ok(_) => console_log!("Successfully updated D1 data for user {}", user_id_clone),
-                Err(e) => {
-                    console_error!("Failed to update D1 data for user {}: {}", user_id_clone, e)
-                }
-            }
-        });
+async fn free_user_name(d1: &D1Database, user_name: &str, user_id: &str) -> Result<String> {
+    // Room for the "_<n>" suffix
+    let base: String = user_name.chars().take(USER_NAME_MAX_LEN - 5).collect();
+    for n in 2..RENAME_ATTEMPTS + 2 {
+        let candidate = format!("{}_{}", base, n);
+        if !login_name_taken(d1, &candidate, user_id).await? {
+            return Ok(candidate);
+        }
     }
+    Err(Error::RustError(format!("No free username for {}", user_id)))
+}
 
-    let mut count = 0;
-    while let Some(_) = futures.next().await {
-        count += 1;
+/// The op the cron reads a user's DO with. Internal, so it skips op rate
+/// limits and still syncs banned players.
+fn sync_request(user_id: &str, rid: &str) -> DurableObjectAugmentedMsg {
+    DurableObjectAugmentedMsg {
+        user_id: user_id.to_string(),
+        op: Op::GetDataInternal,
+        request_id: rid.to_string(),
     }
+}
+
+/// Pull the user's state out of their DO and write it to D1
+async fn sync_user(env: &Env, user_id: &str, rid: &str) -> Result<()> {
+    let d1 = env.d1("D1_DATABASE")?;
+    let user_data_stub = env
+        .durable_object("USER_DATA_WRAPPER")?
+        .id_from_name(user_id)?
+        .get_stub()?;
+
+    let op_request_json = serde_json::to_string(&sync_request(user_id, rid))?;
+
+    let mut request_init = RequestInit::new();
+    request_init.with_method(Method::Post);
+    request_init.with_body(Some(JsValue::from_str(&op_request_json)));
 
-    console_log!("Processed {} user updates.", count);
-    console_log!("Cron job logic finished.");
+    let request_url = "https://internal-do-fetch.com/";
+    let request = Request::new_with_init(request_url, &request_init)?;
+
+    let data: UserData = user_data_stub
+        .fetch_with_request(request)
+        .await?
+        .json()
+        .await?;
+
+    update_user_data(&data, &d1).await?;
+    Ok(())
 }
 
-async fn get_all_user_ids(d1: &D1Database) -> Result<Vec<String>> {
-    console_log!("Attempting to prepare D1 statement for JSON aggregation...");
+pub async fn get_all_user_ids(d1: &D1Database) -> Result<Vec<String>> {
     let statement = d1.prepare("SELECT JSON_GROUP_ARRAY(user_id) AS user_ids FROM user_profile");
-    console_log!("D1 JSON aggregation statement prepared. Executing .first()...");
 
     #[derive(serde::Deserialize, Debug)]
     struct UserIdResult {
         user_ids: String,
     }
 
-    let result_opt: Option<UserIdResult> = match statement.first::<UserIdResult>(None).await {
-        Ok(opt_res) => {
-            console_log!("D1 .first() executed successfully.");
-            opt_res
-        }
-        Err(e) => {
-            console_error!("D1 .first::<UserIdResult>() query failed: {}", e);
-            return Err(e);
-        }
-    };
+    match statement.first::<UserIdResult>(None).await? {
+        Some(result_obj) => serde_json::from_str::<Vec<String>>(&result_obj.user_ids).map_err(|e| {
+            worker::Error::RustError(format!("Failed to parse D1 JSON result string: {}", e))
+        }),
+        None => Ok(Vec::new()),
+    }
+}
 
-    match result_opt {
-        Some(result_obj) => {
-            console_log!("Successfully deserialized D1 result: {:?}", result_obj);
-            let json_string = result_obj.user_ids;
-            match serde_json::from_str::<Vec<String>>(&json_string) {
-                Ok(ids) => {
-                    console_log!(
-                        "Successfully parsed JSON string into Vec<String> ({} users).",
-                        ids.len()
-                    );
-                    Ok(ids)
-                }
-                Err(e) => {
-                    console_error!("Failed to parse user_ids JSON string: {}", e);
-                    Err(worker::Error::RustError(format!(
-                        "Failed to parse D1 JSON result string: {}",
-                        e
-                    )))
-                }
-            }
-        }
-        None => {
-            console_log!("D1 query returned no rows. Returning empty Vec.");
-            Ok(Vec::new())
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_request_reaches_banned_players() {
+        let request = sync_request("banned_player", "rid");
+        assert!(request.op.is_internal());
+        assert!(request.op.ignores_ban());
+    }
+
+    #[test]
+    fn client_ops_are_stopped_by_bans() {
+        assert!(!Op::GetData.ignores_ban());
+        assert!(!Op::SpawnAlien.ignores_ban());
+        assert!(Op::SyncData.ignores_ban());
     }
 }
@@ -0,0 +1,222 @@
+// Daily chest reward track: each tier opens once the player has completed
+// `threshold` daily tasks and pays one bundle drawn by weight from its drops.
+// The DAILY_REWARD_TRACK var replaces the built-in track, e.g.
+// `{"tiers": [{"threshold": 3, "drops": [{"weight": 1, "rewards": [{"Akai": 20}]}]}]}`
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use worker::Env;
+
+use crate::types::{BadgesKind, PowerUpKind, UserData};
+use crate::utils::{calculate_king_alien_lvl, place_alien};
+
+const POWER_UPS: [PowerUpKind; 3] = [
+    PowerUpKind::RowPowerUp,
+    PowerUpKind::ColumnPowerUp,
+    PowerUpKind::NearestSquarePowerUp,
+];
+
+/// One thing a drop hands out
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum RewardItem {
+    // Placed on the grid at level king_lvl * 10 - below_king
+    Alien { below_king: usize },
+    PowerUp(PowerUpKind),
+    RandomPowerUp,
+    Akai(usize),
+    // Skipped if the player already has it
+    Badge(BadgesKind),
+}
+
+/// What a claim actually gave, random picks resolved
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub enum GrantedReward {
+    Alien(usize),
+    PowerUp(PowerUpKind),
+    Akai(usize),
+    Badge(BadgesKind),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RewardDrop {
+    pub weight: f64,
+    pub rewards: Vec<RewardItem>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RewardTier {
+    // Daily tasks completed before the tier can be claimed; also the tier's index
+    pub threshold: usize,
+    pub drops: Vec<RewardDrop>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RewardTrack {
+    pub tiers: Vec<RewardTier>,
+}
+
+/// Why a tier can't be claimed
+#[derive(Debug, PartialEq)]
+pub enum ClaimError {
+    UnknownTier,
+    NotReached { threshold: usize, completed: usize },
+    AlreadyClaimed,
+}
+
+impl ClaimError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ClaimError::UnknownTier => 404,
+            ClaimError::NotReached { .. } => 403,
+            ClaimError::AlreadyClaimed => 409,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ClaimError::UnknownTier => "No daily reward at this index".to_string(),
+            ClaimError::NotReached {
+                threshold,
+                completed,
+            } => format!(
+                "Complete {} daily tasks to claim this reward ({} done)",
+                threshold, completed
+            ),
+            ClaimError::AlreadyClaimed => "Daily reward already claimed".to_string(),
+        }
+    }
+}
+
+impl Default for RewardTrack {
+    // The chest as it shipped: an alien at 3 tasks, a random power-up at 5
+    fn default() -> Self {
+        RewardTrack {
+            tiers: vec![
+                RewardTier {
+                    threshold: 3,
+                    drops: vec![RewardDrop {
+                        weight: 1.0,
+                        rewards: vec![RewardItem::Alien { below_king: 3 }],
+                    }],
+                },
+                RewardTier {
+                    threshold: 5,
+                    drops: vec![RewardDrop {
+                        weight: 1.0,
+                        rewards: vec![RewardItem::RandomPowerUp],
+                    }],
+                },
+            ],
+        }
+    }
+}
+
+impl RewardTrack {
+    /// The DAILY_REWARD_TRACK track, or the built-in one if it's unset or invalid
+    pub fn from_env(env: &Env) -> Self {
+        env.var("DAILY_REWARD_TRACK")
+            .ok()
+            .and_then(|v| serde_json::from_str::<RewardTrack>(&v.to_string()).ok())
+            .filter(|track| track.validate().is_ok())
+            .unwrap_or_default()
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), &'static str> {
+        let mut thresholds: Vec<usize> = self.tiers.iter().map(|t| t.threshold).collect();
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        if thresholds.len() != self.tiers.len() {
+            return Err("tier thresholds must be unique");
+        }
+        for tier in &self.tiers {
+            if tier.drops.is_empty() {
+                return Err("every tier needs at least one drop");
+            }
+            if tier
+                .drops
+                .iter()
+                .any(|drop| !drop.weight.is_finite() || drop.weight < 0.0)
+            {
+                return Err("drop weights must be non-negative numbers");
+            }
+            if !tier.drops.iter().any(|drop| drop.weight > 0.0) {
+                return Err("every tier needs a drop with a positive weight");
+            }
+        }
+        Ok(())
+    }
+
+    /// Claim tier `index` for today: check it, draw a drop and give its rewards
+    pub fn claim(
+        &self,
+        user_data: &mut UserData,
+        index: usize,
+    ) -> std::result::Result<Vec<GrantedReward>, ClaimError> {
+        let tier = self
+            .tiers
+            .iter()
+            .find(|tier| tier.threshold == index)
+            .ok_or(ClaimError::UnknownTier)?;
+        let completed = user_data.daily.total_completed;
+        if completed < tier.threshold {
+            return Err(ClaimError::NotReached {
+                threshold: tier.threshold,
+                completed,
+            });
+        }
+        if user_data.daily.claimed_tiers.contains(&tier.threshold) {
+            return Err(ClaimError::AlreadyClaimed);
+        }
+
+        let mut rng = rand::thread_rng();
+        // validate() guarantees a positive weight, this only guards a hand-built track
+        let rewards = tier
+            .drops
+            .choose_weighted(&mut rng, |drop| drop.weight)
+            .map(|drop| drop.rewards.clone())
+            .unwrap_or_default();
+
+        user_data.daily.claimed_tiers.push(tier.threshold);
+        Ok(rewards
+            .iter()
+            .filter_map(|item| grant(user_data, item, &mut rng))
+            .collect())
+    }
+}
+
+fn grant(user_data: &mut UserData, item: &RewardItem, rng: &mut impl Rng) -> Option<GrantedReward> {
+    let granted = match item {
+        RewardItem::Alien { below_king } => {
+            let level = (user_data.game_state.king_lvl * 10)
+                .saturating_sub(*below_king)
+                .max(1);
+            place_alien(user_data, level);
+            calculate_king_alien_lvl(user_data);
+            user_data.daily.alien_earned = Some(level);
+            GrantedReward::Alien(level)
+        }
+        RewardItem::PowerUp(kind) => grant_power_up(user_data, *kind),
+        RewardItem::RandomPowerUp => {
+            let kind = *POWER_UPS.choose(rng)?;
+            grant_power_up(user_data, kind)
+        }
+        RewardItem::Akai(amount) => {
+            user_data.progress.akai_balance += amount;
+            GrantedReward::Akai(*amount)
+        }
+        RewardItem::Badge(badge) => {
+            if user_data.progress.badges.contains(badge) {
+                return None;
+            }
+            user_data.progress.badges.push(badge.clone());
+            GrantedReward::Badge(badge.clone())
+        }
+    };
+    Some(granted)
+}
+
+fn grant_power_up(user_data: &mut UserData, kind: PowerUpKind) -> GrantedReward {
+    user_data.game_state.power_ups.push(kind);
+    user_data.daily.pu_earned = Some(kind);
+    GrantedReward::PowerUp(kind)
+}
@@ -0,0 +1,42 @@
+// Elo-style annotation skill. Every scored datapoint is a match between the
+// player and the datapoint's difficulty: agreeing with the consensus is a win,
+// being flagged a loss.
+
+// Where new players and unrated datapoints start
+pub const DEFAULT_SKILL: f64 = 1000.0;
+pub const DEFAULT_DIFFICULTY: f64 = 1000.0;
+
+// Largest change a single datapoint can make
+const K_FACTOR: f64 = 32.0;
+
+// Skill is kept inside this range so one streak can't run away
+const MIN_SKILL: f64 = 100.0;
+const MAX_SKILL: f64 = 3000.0;
+
+pub fn default_skill() -> f64 {
+    DEFAULT_SKILL
+}
+
+pub fn default_difficulty() -> f64 {
+    DEFAULT_DIFFICULTY
+}
+
+/// Chance a player of `skill` agrees with the consensus on a datapoint of `difficulty`
+pub fn expected_score(skill: f64, difficulty: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((difficulty - skill) / 400.0))
+}
+
+/// Skill after one scored datapoint; `score` is 1.0 for agreeing, 0.0 for flagged
+pub fn updated_skill(skill: f64, difficulty: f64, score: f64) -> f64 {
+    let skill = if skill.is_finite() {
+        skill
+    } else {
+        DEFAULT_SKILL
+    };
+    let difficulty = if difficulty.is_finite() {
+        difficulty
+    } else {
+        DEFAULT_DIFFICULTY
+    };
+    (skill + K_FACTOR * (score - expected_score(skill, difficulty))).clamp(MIN_SKILL, MAX_SKILL)
+}
@@ -1,14 +1,81 @@
 use crate::notification::Read;
 use serde::{Deserialize, Serialize};
-use worker::{D1Database, Response, Result};
+use worker::{D1Database, Result};
 
+use crate::login_guard;
 use crate::types::UserData;
 use crate::utils::league_to_string;
 use crate::utils::{convert_badges_to_json, convert_power_ups_to_json};
 use crate::JsValue;
 use serde_json::to_string as to_json;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Columns added after their table first shipped; CREATE TABLE IF NOT EXISTS
+// won't add them to an existing database, so they are patched in on startup
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("user_profile", "email_verified", "INTEGER NOT NULL DEFAULT 0"),
+    ("user_profile", "annotation_skill", "REAL NOT NULL DEFAULT 1000"),
+    ("user_profile", "utc_offset_minutes", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+// Unique indexes added after their table shipped. Building one fails while the
+// table still has case-insensitive duplicates, which the cron renames first.
+const UNIQUE_INDEXES: &[&str] = &[
+    "CREATE UNIQUE INDEX IF NOT EXISTS idx_user_name ON user_profile(user_name COLLATE NOCASE)",
+    "CREATE UNIQUE INDEX IF NOT EXISTS idx_referal_code ON social_data(referal_code COLLATE NOCASE)",
+];
+
+static SCHEMA_READY: AtomicBool = AtomicBool::new(false);
+
+// Every (table, column) holding rows that belong to a user. Used for account
+// deletion and data export, so new per-user tables must be listed here.
+// Ordered children first so deletes don't trip the foreign keys. Rows that
+// record other players' history (their redemption of this user's code, what
+// they earned from this user) are not this user's and stay.
+pub const USER_OWNED_ROWS: &[(&str, &str)] = &[
+    ("notifications", "user_id"),
+    ("label_submissions", "user_id"),
+    ("pending_deliveries", "user_id"),
+    ("video_task_assignments", "user_id"),
+    ("auth_tokens", "user_id"),
+    ("friends", "requester_id"),
+    ("friends", "addressee_id"),
+    ("referral_rewards", "beneficiary_id"),
+    ("referral_redemptions", "referee_id"),
+    ("leaderboard_data", "user_id"),
+    ("game_state", "user_id"),
+    ("progress", "user_id"),
+    ("social_data", "user_id"),
+    ("user_data", "user_id"),
+    ("user_profile", "user_id"),
+];
+
+/// Create whatever tables, columns and indexes are missing, once per isolate.
+/// Run by the DO before its first op and by the cron.
+pub async fn ensure_schema(d1: &D1Database) -> Result<()> {
+    if SCHEMA_READY.load(Ordering::Relaxed) {
+        return Ok(());
+    }
 
-pub async fn create_table_if_not_exists(d1: &D1Database) -> Result<Response> {
+    create_table_if_not_exists(d1).await?;
+    for (table, column, definition) in ADDED_COLUMNS {
+        add_column_if_missing(d1, table, column, definition).await?;
+    }
+    // Left for a later call while duplicates block a unique index; ops don't need it
+    if create_unique_indexes(d1).await.is_ok() {
+        SCHEMA_READY.store(true, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+pub async fn create_unique_indexes(d1: &D1Database) -> Result<()> {
+    for index in UNIQUE_INDEXES {
+        d1.prepare(*index).run().await?;
+    }
+    Ok(())
+}
+
+async fn create_table_if_not_exists(d1: &D1Database) -> Result<()> {
     // SQLite doesn't support ENUM types or array types, so we need to modify our approach
     let stmt = d1.prepare(
         r#"
@@ -20,7 +87,8 @@ pub async fn create_table_if_not_exists(d1: &D1Database) -> Result<Response> {
         user_name TEXT,
         password TEXT,
         last_login INTEGER NOT NULL,
-        real_login INTEGER NOT NULL
+        real_login INTEGER NOT NULL,
+        email_verified INTEGER NOT NULL DEFAULT 0
     );
 
     -- Create GameState table
@@ -87,13 +155,192 @@ pub async fn create_table_if_not_exists(d1: &D1Database) -> Result<Response> {
     FOREIGN KEY (user_id) REFERENCES user_profile(user_id)
     );
 
+    -- Create Friends table, one row per pair (requester -> addressee)
+    CREATE TABLE IF NOT EXISTS friends (
+        requester_id TEXT NOT NULL,
+        addressee_id TEXT NOT NULL,
+        status TEXT NOT NULL, -- "Pending" or "Accepted"
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (requester_id, addressee_id),
+        FOREIGN KEY (requester_id) REFERENCES user_profile(user_id),
+        FOREIGN KEY (addressee_id) REFERENCES user_profile(user_id)
+    );
+
+    -- Create ReferralRedemptions table, at most one row per referee
+    CREATE TABLE IF NOT EXISTS referral_redemptions (
+        referee_id TEXT PRIMARY KEY,
+        referrer_id TEXT NOT NULL,
+        code TEXT NOT NULL,
+        redeemed_at INTEGER NOT NULL,
+        credited_at INTEGER, -- NULL until the referrer has been rewarded
+        -- No key on referrer_id: the referee keeps their redemption if the referrer deletes their account
+        FOREIGN KEY (referee_id) REFERENCES user_profile(user_id)
+    );
+
+    -- Create ReferralRewards table, a ledger of every referral payout
+    CREATE TABLE IF NOT EXISTS referral_rewards (
+        reward_id INTEGER PRIMARY KEY AUTOINCREMENT,
+        beneficiary_id TEXT NOT NULL,
+        referee_id TEXT NOT NULL, -- the new player the reward was earned from
+        tier INTEGER NOT NULL, -- 1 = direct referral, 2 = referral of a referral
+        akai INTEGER NOT NULL,
+        social_score INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        FOREIGN KEY (beneficiary_id) REFERENCES user_profile(user_id)
+    );
+
+    -- Create AuthTokens table for email verification and password reset
+    CREATE TABLE IF NOT EXISTS auth_tokens (
+        token_hash TEXT PRIMARY KEY, -- sha256 of the token, the raw value is only mailed
+        user_id TEXT NOT NULL,
+        purpose TEXT NOT NULL, -- "EmailVerification" or "PasswordReset"
+        email TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL,
+        used_at INTEGER, -- NULL until redeemed, tokens are single-use
+        FOREIGN KEY (user_id) REFERENCES user_profile(user_id)
+    );
+
+    -- Create LoginAttempts table, keyed by "user:<id>" or "ip:<address>"
+    CREATE TABLE IF NOT EXISTS login_attempts (
+        attempt_key TEXT PRIMARY KEY,
+        failures INTEGER NOT NULL,
+        locked_until INTEGER NOT NULL, -- unix seconds, in the past when not blocked
+        last_failure_at INTEGER NOT NULL
+    );
+
+    -- Create DailyLinks table, the pool daily social tasks are drawn from
+    CREATE TABLE IF NOT EXISTS daily_links (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        url TEXT NOT NULL UNIQUE,
+        platform TEXT NOT NULL,
+        reward INTEGER NOT NULL,
+        weight REAL NOT NULL DEFAULT 1,
+        starts_at INTEGER, -- unix seconds, NULL = no bound
+        ends_at INTEGER,
+        enabled INTEGER NOT NULL DEFAULT 1,
+        created_at INTEGER NOT NULL
+    );
+
+    -- Create BannedUsers table; expires_at NULL means permanent
+    CREATE TABLE IF NOT EXISTS banned_users (
+        user_id TEXT PRIMARY KEY,
+        reason TEXT NOT NULL,
+        banned_by TEXT NOT NULL,
+        banned_at INTEGER NOT NULL,
+        expires_at INTEGER
+    );
+
+    -- Create AdminAuditLog table, one row per admin API call
+    CREATE TABLE IF NOT EXISTS admin_audit_log (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        admin_name TEXT NOT NULL,
+        admin_role TEXT NOT NULL,
+        action TEXT NOT NULL,
+        target TEXT,
+        details TEXT,
+        status INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+
+    -- Create LabelSubmissions table, every player's label per datapoint for consensus scoring
+    CREATE TABLE IF NOT EXISTS label_submissions (
+        datapoint_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        label TEXT NOT NULL, -- LabelSubmission JSON
+        corrected_label TEXT NOT NULL, -- PreLabel JSON
+        submitted_at INTEGER NOT NULL,
+        PRIMARY KEY (datapoint_id, user_id)
+    );
+
+    -- Create DatapointResults table; a row means the datapoint's rewards were paid
+    CREATE TABLE IF NOT EXISTS datapoint_results (
+        datapoint_id TEXT PRIMARY KEY,
+        source TEXT NOT NULL, -- 'consensus' or 'override'
+        player_ranking TEXT NOT NULL,
+        flagged_players TEXT NOT NULL,
+        resolved_at INTEGER NOT NULL
+    );
+
+    -- Create PendingDeliveries table, task rewards the cron still has to deliver
+    CREATE TABLE IF NOT EXISTS pending_deliveries (
+        datapoint_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        last_error TEXT,
+        queued_at INTEGER NOT NULL,
+        PRIMARY KEY (datapoint_id, user_id)
+    );
+
+    -- Create VideoTaskPool table, datapoints prefetched from the labeling backend
+    CREATE TABLE IF NOT EXISTS video_task_pool (
+        datapoint_id TEXT PRIMARY KEY,
+        task_id TEXT NOT NULL,
+        media_url TEXT NOT NULL,
+        pre_label TEXT NOT NULL, -- PreLabel JSON
+        fetched_at INTEGER NOT NULL,
+        assigned_count INTEGER NOT NULL DEFAULT 0,
+        difficulty REAL NOT NULL DEFAULT 1000 -- Elo-style, see skill.rs
+    );
+
+    -- Create VideoTaskAssignments table, which datapoints each player was given
+    CREATE TABLE IF NOT EXISTS video_task_assignments (
+        user_id TEXT NOT NULL,
+        datapoint_id TEXT NOT NULL,
+        assigned_at INTEGER NOT NULL,
+        PRIMARY KEY (user_id, datapoint_id)
+    );
+
+    -- Create QuestChains table, the weekly / monthly quest chains dealt to players
+    CREATE TABLE IF NOT EXISTS quest_chains (
+        id TEXT PRIMARY KEY,
+        title TEXT NOT NULL,
+        duration TEXT NOT NULL, -- 'Weekly' or 'Monthly'
+        steps TEXT NOT NULL, -- QuestStep JSON array
+        enabled INTEGER NOT NULL DEFAULT 1,
+        updated_at INTEGER NOT NULL
+    );
 
     CREATE INDEX IF NOT EXISTS idx_product ON progress(product);
+    CREATE INDEX IF NOT EXISTS idx_friends_addressee ON friends(addressee_id);
+    CREATE INDEX IF NOT EXISTS idx_referral_referrer ON referral_redemptions(referrer_id);
+    CREATE INDEX IF NOT EXISTS idx_referral_rewards_beneficiary ON referral_rewards(beneficiary_id);
+    CREATE INDEX IF NOT EXISTS idx_admin_audit_target ON admin_audit_log(target, created_at);
+    CREATE INDEX IF NOT EXISTS idx_video_task_pool_assigned ON video_task_pool(assigned_count, fetched_at);
     "#,
     );
 
     stmt.run().await?;
-    Response::ok("Tables created successfully!")
+    Ok(())
+}
+
+async fn add_column_if_missing(
+    d1: &D1Database,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    #[derive(Deserialize)]
+    struct ColumnInfo {
+        name: String,
+    }
+
+    let columns = d1
+        .prepare(format!("PRAGMA table_info({})", table))
+        .all()
+        .await?
+        .results::<ColumnInfo>()?;
+
+    if !columns.iter().any(|c| c.name == column) {
+        d1.prepare(format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))
+        .run()
+        .await?;
+    }
+
+    Ok(())
 }
 
 pub async fn insert_new_user(data: &UserData, d1: &D1Database) -> Result<()> {
@@ -101,7 +348,7 @@ pub async fn insert_new_user(data: &UserData, d1: &D1Database) -> Result<()> {
 
     // Insert into user_profile
     let stmt_profile = d1
-        .prepare("INSERT INTO user_profile (user_name,password, user_id, email, pfp, last_login,real_login) VALUES (?, ?, ?, ?, ?,?,?)");
+        .prepare("INSERT INTO user_profile (user_name,password, user_id, email, pfp, last_login,real_login, email_verified) VALUES (?, ?, ?, ?, ?,?,?,?)");
     stmt_profile
         .bind(&[
             data.profile
@@ -123,6 +370,7 @@ pub async fn insert_new_user(data: &UserData, d1: &D1Database) -> Result<()> {
             data.profile.pfp.into(),
             (data.profile.last_login as f64).into(),
             (data.profile.last_login as f64).into(),
+            (data.profile.email_verified as u8).into(),
         ])?
         .run()
         .await?;
@@ -232,7 +480,7 @@ pub async fn update_user_data(data: &UserData, d1: &D1Database) -> Result<()> {
 
     // Update user_profile
     let stmt_profile =
-        d1.prepare("UPDATE user_profile SET user_name = ?,password = ?, email = ?, pfp = ?, last_login = ? , real_login = ? WHERE user_id = ?");
+        d1.prepare("UPDATE user_profile SET user_name = ?,password = ?, email = ?, pfp = ?, last_login = ? , real_login = ?, email_verified = ?, annotation_skill = ?, utc_offset_minutes = ? WHERE user_id = ?");
     stmt_profile
         .bind(&[
             data.profile
@@ -253,6 +501,9 @@ pub async fn update_user_data(data: &UserData, d1: &D1Database) -> Result<()> {
             data.profile.pfp.into(),
             (data.profile.last_login as f64).into(),
             (data.profile.real_login as f64).into(),
+            (data.profile.email_verified as u8).into(),
+            data.profile.annotation_skill.into(),
+            data.profile.utc_offset_minutes.into(),
             user_id.into(), // WHERE clause
         ])?
         .run()
@@ -363,10 +614,116 @@ pub async fn get_user_credentials(
     user_id: &str,
 ) -> Result<Option<UserCredentials>> {
     let stmt = d1
-        .prepare("SELECT user_id, password, user_name FROM user_profile WHERE user_id = ? OR user_name = ?")
-        .bind(&[JsValue::from(user_id), JsValue::from(user_id)])?;
+        .prepare("SELECT user_id, password, user_name FROM user_profile WHERE user_id = ?1 OR user_name = ?1 ORDER BY user_id = ?1 DESC")
+        .bind(&[JsValue::from(user_id)])?;
 
     let result: Option<UserCredentials> = stmt.first(None).await?;
 
     Ok(result)
 }
+
+/// Whether `name` is already used by another player, either as their username
+/// or as their user id (both are accepted as a login)
+pub async fn login_name_taken(d1: &D1Database, name: &str, user_id: &str) -> Result<bool> {
+    let stmt = d1
+        .prepare("SELECT 1 AS taken FROM user_profile WHERE (user_name = ?1 COLLATE NOCASE OR user_id = ?1 COLLATE NOCASE) AND user_id != ?2")
+        .bind(&[JsValue::from(name), JsValue::from(user_id)])?;
+
+    Ok(stmt.first::<usize>(Some("taken")).await?.is_some())
+}
+
+pub async fn update_user_name(d1: &D1Database, user_id: &str, user_name: Option<&str>) -> Result<()> {
+    d1.prepare("UPDATE user_profile SET user_name = ? WHERE user_id = ?")
+        .bind(&[
+            user_name.map(JsValue::from).unwrap_or_else(JsValue::null),
+            JsValue::from(user_id),
+        ])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// Whether another account already has `email` verified
+pub async fn email_taken(d1: &D1Database, email: &str, user_id: &str) -> Result<bool> {
+    let stmt = d1
+        .prepare("SELECT 1 AS taken FROM user_profile WHERE email = ? COLLATE NOCASE AND email_verified = 1 AND user_id != ?")
+        .bind(&[JsValue::from(email), JsValue::from(user_id)])?;
+
+    Ok(stmt.first::<usize>(Some("taken")).await?.is_some())
+}
+
+pub async fn update_email(d1: &D1Database, user_id: &str, email: &str, verified: bool) -> Result<()> {
+    d1.prepare("UPDATE user_profile SET email = ?, email_verified = ? WHERE user_id = ?")
+        .bind(&[
+            JsValue::from(email),
+            JsValue::from(verified as u8),
+            JsValue::from(user_id),
+        ])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+pub async fn update_password(d1: &D1Database, user_id: &str, password_hash: &str) -> Result<()> {
+    d1.prepare("UPDATE user_profile SET password = ? WHERE user_id = ?")
+        .bind(&[JsValue::from(password_hash), JsValue::from(user_id)])?
+        .run()
+        .await?;
+
+    Ok(())
+}
+
+/// Remove every D1 row belonging to a user in one transaction
+pub async fn delete_user(d1: &D1Database, user_id: &str) -> Result<()> {
+    let mut statements = USER_OWNED_ROWS
+        .iter()
+        .map(|(table, column)| {
+            d1.prepare(format!("DELETE FROM {} WHERE {} = ?", table, column))
+                .bind(&[JsValue::from(user_id)])
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // Keyed by "user:<id>" rather than by user id
+    statements.push(
+        d1.prepare("DELETE FROM login_attempts WHERE attempt_key = ?")
+            .bind(&[login_guard::account_key(user_id).into()])?,
+    );
+
+    d1.batch(statements).await?;
+    Ok(())
+}
+
+/// Every D1 row belonging to a user, grouped by table
+pub async fn export_user_rows(
+    d1: &D1Database,
+    user_id: &str,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let mut tables = serde_json::Map::new();
+
+    for (table, column) in USER_OWNED_ROWS {
+        let mut rows = d1
+            .prepare(format!("SELECT * FROM {} WHERE {} = ?", table, column))
+            .bind(&[JsValue::from(user_id)])?
+            .all()
+            .await?
+            .results::<serde_json::Value>()?;
+
+        // Credentials are not personal data worth handing out
+        for row in rows.iter_mut() {
+            if let Some(obj) = row.as_object_mut() {
+                obj.remove("password");
+                obj.remove("token_hash");
+            }
+        }
+
+        let entry = tables
+            .entry(table.to_string())
+            .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+        if let serde_json::Value::Array(existing) = entry {
+            existing.extend(rows);
+        }
+    }
+
+    Ok(tables)
+}
@@ -0,0 +1,137 @@
+// Daily streak: one more for every local day on which the player finishes
+// enough of their daily tasks. A missed day breaks it unless the player holds
+// a streak freeze, which covers one missed day each.
+use chrono::{Duration, NaiveDate};
+use std::collections::HashMap;
+
+use crate::notification::{new_notification, NotificationType, RewardPayload};
+use crate::types::UserData;
+
+// Daily tasks (links and counters) that make a day count towards the streak
+pub const STREAK_DAY_MIN_COMPLETED: usize = 3;
+
+pub const STREAK_FREEZE_COST: usize = 50; // Akai
+pub const MAX_STREAK_FREEZES: usize = 2;
+
+pub struct StreakMilestone {
+    pub days: usize,
+    pub akai_balance: usize,
+    pub social_score: usize,
+    pub freezes: usize,
+}
+
+// Paid every time a streak reaches `days`
+pub const STREAK_MILESTONES: &[StreakMilestone] = &[
+    StreakMilestone {
+        days: 3,
+        akai_balance: 10,
+        social_score: 2,
+        freezes: 0,
+    },
+    StreakMilestone {
+        days: 7,
+        akai_balance: 25,
+        social_score: 5,
+        freezes: 1,
+    },
+    StreakMilestone {
+        days: 14,
+        akai_balance: 50,
+        social_score: 10,
+        freezes: 0,
+    },
+    StreakMilestone {
+        days: 30,
+        akai_balance: 100,
+        social_score: 20,
+        freezes: 1,
+    },
+    StreakMilestone {
+        days: 100,
+        akai_balance: 500,
+        social_score: 50,
+        freezes: 2,
+    },
+];
+
+fn parse_day(day: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()
+}
+
+fn days_between(from: &str, to: &str) -> Option<i64> {
+    Some((parse_day(to)? - parse_day(from)?).num_days())
+}
+
+/// At the daily rollover to `today`: cover the days missed since the last
+/// counted day with freezes, or break the streak. Returns the freezes spent.
+pub fn roll_over(user_data: &mut UserData, today: &str) -> usize {
+    let progress = &mut user_data.progress;
+    let Some(last) = progress.streak_last_day.as_deref() else {
+        return 0;
+    };
+    let missed = days_between(last, today).map_or(0, |days| (days - 1).max(0)) as usize;
+    if missed == 0 {
+        return 0;
+    }
+
+    if missed <= progress.streak_freezes {
+        progress.streak_freezes -= missed;
+        progress.streak_last_day =
+            parse_day(today).map(|day| (day - Duration::days(1)).format("%Y-%m-%d").to_string());
+        missed
+    } else {
+        progress.streak = 0;
+        0
+    }
+}
+
+/// Count the current day once enough daily tasks are done. Call after
+/// `daily.total_completed` goes up.
+pub fn record_completion(user_data: &mut UserData) {
+    let Some(day) = user_data.daily.day.clone() else {
+        return;
+    };
+    let progress = &mut user_data.progress;
+    if user_data.daily.total_completed < STREAK_DAY_MIN_COMPLETED
+        || progress.streak_last_day.as_deref() == Some(day.as_str())
+    {
+        return;
+    }
+
+    let continues = progress
+        .streak_last_day
+        .as_deref()
+        .is_some_and(|last| days_between(last, &day) == Some(1));
+    progress.streak = if continues { progress.streak + 1 } else { 1 };
+    progress.streak_last_day = Some(day);
+    progress.best_streak = progress.best_streak.max(progress.streak);
+
+    let streak = progress.streak;
+    if let Some(milestone) = STREAK_MILESTONES.iter().find(|m| m.days == streak) {
+        pay_milestone(user_data, milestone);
+    }
+}
+
+fn pay_milestone(user_data: &mut UserData, milestone: &StreakMilestone) {
+    let reward = RewardPayload {
+        akai_balance: milestone.akai_balance as i64,
+        iq: 0,
+        social_score: milestone.social_score as i64,
+    };
+    let applied = reward.apply(&mut user_data.progress);
+    user_data.progress.streak_freezes =
+        (user_data.progress.streak_freezes + milestone.freezes).min(MAX_STREAK_FREEZES);
+
+    let mut metadata = HashMap::new();
+    applied.write_metadata(&mut metadata);
+    metadata.insert("streak".to_string(), milestone.days.to_string());
+    metadata.insert("streak_freezes".to_string(), milestone.freezes.to_string());
+
+    let notification = new_notification(
+        &user_data.profile.user_id,
+        NotificationType::System,
+        &format!("{}-day streak! Keep it going.", milestone.days),
+        Some(metadata),
+    );
+    user_data.notifications.push(notification);
+}
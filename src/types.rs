@@ -1,13 +1,12 @@
 use chrono::Utc;
-use rand::{
-    distributions::{Alphanumeric, DistString},
-    thread_rng, Rng,
-};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use worker::{console_log, Date};
+use worker::Date;
 
 use crate::notification::{Notification, Read};
+use crate::quests::QuestSlot;
+use crate::referral::random_referral_code;
+use crate::skill::{default_difficulty, default_skill, DEFAULT_SKILL};
 use crate::{
     daily_task::{Links, SocialPlatform},
     notification::NotificationType,
@@ -20,29 +19,99 @@ pub enum Op {
     DeleteAlienFromActive(usize),
     UsePowerup(usize, usize), //changed
     SpawnPowerup(PowerUpKind),
+    BuyStreakFreeze,
     GetData,
+    GetDataInternal, // internal, sent by the cron's D1 sync
     Register(String),
     AwardBadge(BadgesKind),
     UpdateEmail(String),
+    MarkEmailVerified(String), // internal, sent by the /api/verify_email link
     UpdatePfp(usize),
+    UpdateTimezone(i32), // minutes east of UTC
     UpdateIq(usize),
     IncrementAkaiBalance,
     DecrementAkaiBalance,
     MoveAlienFromInventoryToActive,
     UpdateUserName(Option<String>),
+    RenameInternal(String), // internal, sent by the cron to resolve a duplicate username
     UpdatePassword(String),
+    ResetPassword(String), // internal, sent by /api/password_reset/confirm
     MoveAlienInGrid(usize, usize),
     AddNotificationInternal(Notification),
     MarkNotificationRead(String),
     UseReferralCode(String),
+    ClaimVanityCode(String),
+    SetReferralCodeInternal(String), // internal, sent by the cron to replace a duplicate code
+    GetReferrals,
+    SendFriendRequest(String), // target user_id
+    AcceptFriendRequest(String),
+    RemoveFriend(String), // also declines / cancels pending requests
+    GetFriends,
     UpdateDbFromDo,
     GenerateDailyTasks,
-    CheckDailyTask(Option<String>),
+    GetQuests,
+    CheckDailyTask(Option<String>), // url ignored, visits are recorded by /r/{token}
+    RecordLinkVisit(String),        // internal, sent by the /r/{token} redirect
     ClaimDailyReward(usize),
     SyncData,
+    DeleteAccount(String), // password confirmation
+    ExportMyData,
+    Admin(AdminOp), // internal, sent by the /api/admin routes
     alien,
     inv,
-    SubmitVideoLabel(String, String), // (datapoint_id, label)
+    SubmitVideoLabel(String, LabelSubmission), // (datapoint_id, label)
+}
+
+impl Op {
+    /// Variant name, e.g. "CombineAlien", used to key per-op settings
+    pub fn kind(&self) -> String {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => name,
+            Ok(serde_json::Value::Object(map)) => map.keys().next().cloned().unwrap_or_default(),
+            _ => String::new(),
+        }
+    }
+
+    /// Ops only the worker itself may send to a user's DO, never a client socket
+    pub fn is_internal(&self) -> bool {
+        matches!(
+            self,
+            Op::Register(_)
+                | Op::GetDataInternal
+                | Op::AddNotificationInternal(_)
+                | Op::MarkEmailVerified(_)
+                | Op::ResetPassword(_)
+                | Op::RenameInternal(_)
+                | Op::SetReferralCodeInternal(_)
+                | Op::RecordLinkVisit(_)
+                | Op::Admin(_)
+        )
+    }
+
+    /// Ops a banned player's DO still runs: the worker's own and admin force-syncs
+    pub fn ignores_ban(&self) -> bool {
+        self.is_internal() || matches!(self, Op::SyncData)
+    }
+}
+
+/// Something an admin can hand out or take back
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Grant {
+    AkaiBalance(usize),
+    SocialScore(usize),
+    Iq(usize),
+    InventoryAliens(usize),
+    PowerUp(PowerUpKind),
+    Badge(BadgesKind),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AdminOp {
+    Inspect,
+    // Replaces the stored state; the profile's identity fields are kept
+    Replace(Box<UserData>),
+    Grant(Grant),
+    Revoke(Grant),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -50,10 +119,22 @@ pub struct WsMsg {
     pub op: Op,
 }
 
+/// Machine-readable error sent back over the socket (and as an HTTP body)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WsError {
+    pub error: String,
+    pub code: u16,
+    pub message: String,
+    pub retry_after_secs: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DurableObjectAugmentedMsg {
     pub user_id: String,
     pub op: Op,
+    // Correlates the DO's log lines with the worker request that sent the op
+    #[serde(default)]
+    pub request_id: String,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Copy)]
@@ -103,18 +184,28 @@ pub struct LeaderboardData {
     pub global: usize,
 }
 
-#[derive(Deserialize, Clone, Debug, Serialize)]
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
 pub struct UserProfile {
     pub user_id: String,
     pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
     pub pfp: usize,
     pub user_name: Option<String>,
     pub password: Option<String>,
     pub last_login: u64,
     pub real_login:u64,
+    // Elo-style rating from scored labels, see skill.rs
+    #[serde(default = "default_skill")]
+    pub annotation_skill: f64,
+    // Daily tasks roll over at the player's local midnight
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+    #[serde(default)]
+    pub timezone_changed_at: u64,
 }
 
-#[derive(Deserialize, Clone, Debug, Serialize)]
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
 pub struct GameState {
     pub active_aliens: [usize; 16],
     pub inventory_aliens: usize,
@@ -123,7 +214,7 @@ pub struct GameState {
     pub total_merged_aliens: usize,
 }
 
-#[derive(Deserialize, Clone, Debug, Serialize)]
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
 pub struct Progress {
     pub iq: usize,
     pub social_score: usize,
@@ -133,6 +224,13 @@ pub struct Progress {
     pub total_task_completed: usize,
     pub streak: usize,
     pub badges: Vec<BadgesKind>,
+    // Local day (see DailyProgress::day) the streak was last extended, see streak.rs
+    #[serde(default)]
+    pub streak_last_day: Option<String>,
+    #[serde(default)]
+    pub streak_freezes: usize,
+    #[serde(default)]
+    pub best_streak: usize,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -142,6 +240,8 @@ pub struct VideoTask {
     pub media_url: String,
     pub pre_label: PreLabel,
     pub visited: bool,
+    #[serde(default = "default_difficulty")]
+    pub difficulty: f64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -151,7 +251,81 @@ pub struct PreLabel {
     pub keywords: Vec<String>,
 }
 
-#[derive(Deserialize, Clone, Debug, Serialize)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PreLabelVerdict {
+    Accept,
+    Reject,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MapPosition {
+    pub lat: f64,
+    pub lng: f64,
+}
+
+/// Part of the video the label refers to, in seconds from the start
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TimeSpan {
+    pub start_secs: f64,
+    pub end_secs: f64,
+}
+
+/// A player's annotation of a datapoint. `Accept` takes the prelabel as is;
+/// `Reject` must carry at least one correction.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LabelSubmission {
+    pub verdict: PreLabelVerdict,
+    #[serde(default)]
+    pub summary: Option<String>,
+    #[serde(default)]
+    pub keywords_added: Vec<String>,
+    #[serde(default)]
+    pub keywords_removed: Vec<String>,
+    #[serde(default)]
+    pub map_position: Option<MapPosition>,
+    #[serde(default)]
+    pub time_spans: Vec<TimeSpan>,
+}
+
+impl LabelSubmission {
+    pub fn has_corrections(&self) -> bool {
+        self.summary.is_some()
+            || !self.keywords_added.is_empty()
+            || !self.keywords_removed.is_empty()
+            || self.map_position.is_some()
+            || !self.time_spans.is_empty()
+    }
+
+    /// The prelabel with this submission's corrections applied
+    pub fn corrected(&self, pre_label: &PreLabel) -> PreLabel {
+        let mut keywords: Vec<String> = pre_label
+            .keywords
+            .iter()
+            .filter(|kw| {
+                !self
+                    .keywords_removed
+                    .iter()
+                    .any(|removed| removed.trim().eq_ignore_ascii_case(kw))
+            })
+            .cloned()
+            .collect();
+        keywords.extend(self.keywords_added.iter().map(|kw| kw.trim().to_string()));
+
+        PreLabel {
+            map_position: self.map_position.map_or_else(
+                || pre_label.map_position.clone(),
+                |pos| format!("{},{}", pos.lat, pos.lng),
+            ),
+            summary: self
+                .summary
+                .as_ref()
+                .map_or_else(|| pre_label.summary.clone(), |s| s.trim().to_string()),
+            keywords,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
 pub struct DailyProgress {
     pub links: Vec<Links>,
     pub daily_merge: (usize, usize, bool),
@@ -161,15 +335,35 @@ pub struct DailyProgress {
     pub alien_earned: Option<usize>,
     pub pu_earned: Option<PowerUpKind>,
     pub video_tasks: Vec<VideoTask>, // <-- NEW
+    // Player's local date these tasks were generated for, None before the first generation
+    #[serde(default)]
+    pub day: Option<String>,
+    // Unix time of the local midnight that ends `day`
+    #[serde(default)]
+    pub resets_at: i64,
+    // Social score taken for leaving the previous day incomplete
+    #[serde(default)]
+    pub missed_penalty: usize,
+    // Tasks the day should have; CheckDailyTask tops up when the pool ran short
+    #[serde(default)]
+    pub video_tasks_target: usize,
+    // Thresholds of the reward track tiers claimed today
+    #[serde(default)]
+    pub claimed_tiers: Vec<usize>,
 }
 
-#[derive(Deserialize, Clone, Debug, Serialize)]
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
 pub struct SocialData {
     pub players_referred: usize,
     pub referal_code: String,
+    #[serde(default)]
+    pub referred_by: Option<String>,
+    // Referrer still waiting for us to reach the referral milestone
+    #[serde(default)]
+    pub pending_referrer: Option<String>,
 }
 
-#[derive(Deserialize, Clone, Debug, Serialize)]
+#[derive(Deserialize, Clone, Debug, Serialize, PartialEq)]
 pub struct UserData {
     pub profile: UserProfile,
     pub game_state: GameState,
@@ -178,20 +372,32 @@ pub struct UserData {
     pub league: LeagueType,
     pub notifications: Vec<Notification>, // <-- added this
     pub daily: DailyProgress,
+    // Reward keys already paid out, oldest first, capped at MAX_APPLIED_REWARDS
+    #[serde(default)]
+    pub applied_rewards: Vec<String>,
+    // Weekly and monthly quest chains dealt for the current periods
+    #[serde(default)]
+    pub quests: Vec<QuestSlot>,
 }
 
+// Retried deliveries arrive within minutes, so the most recent keys are enough
+pub const MAX_APPLIED_REWARDS: usize = 500;
+
 impl Default for UserData {
     fn default() -> Self {
-        console_log!("defaulting user data");
         let mut res = Self {
             profile: UserProfile {
                 user_id: "kunal".to_string(),
                 email: None,
+                email_verified: false,
                 pfp: 1,
                 user_name: None,
                 password: Some("123456".to_string()),
                 last_login: Date::now().as_millis() / 1000,
                 real_login: Date::now().as_millis() / 1000,
+                annotation_skill: DEFAULT_SKILL,
+                utc_offset_minutes: 0,
+                timezone_changed_at: 0,
             },
             game_state: GameState {
                 active_aliens: [0; 16],
@@ -209,20 +415,26 @@ impl Default for UserData {
                 total_task_completed: 0,
                 streak: 0,
                 badges: Vec::new(),
+                streak_last_day: None,
+                streak_freezes: 0,
+                best_streak: 0,
             },
             social: SocialData {
                 players_referred: 0,
-                referal_code: thread_rng()
-                    .sample_iter(Alphanumeric)
-                    .take(8)
-                    .map(|b| b as char)
-                    .collect(),
+                referal_code: random_referral_code(),
+                referred_by: None,
+                pending_referrer: None,
             },
             league: LeagueType::Bronze,
             notifications: Vec::new(), // <-- added this,
             daily: DailyProgress {
                 links: Vec::new(),
                 video_tasks: Vec::new(), // <-- added here
+                video_tasks_target: 0,
+                claimed_tiers: Vec::new(),
+                day: None,
+                resets_at: 0,
+                missed_penalty: 0,
                 daily_merge: (0, 0, false),
                 daily_annotate: (0, 0, false),
                 daily_powerups: (0, 0, false),
@@ -230,6 +442,8 @@ impl Default for UserData {
                 alien_earned: None,
                 pu_earned: None,
             },
+            applied_rewards: Vec::new(),
+            quests: Vec::new(),
         };
 
         for i in 0..5 {
@@ -244,6 +458,7 @@ impl Default for UserData {
             timestamp: Utc::now().timestamp(),
             read: Read::No,
             metadata: None, // 👈 No metadata
+            reward_key: None,
         });
 
         res
@@ -251,26 +466,60 @@ impl Default for UserData {
 }
 
 impl UserData {
-    pub fn calculate_last_login(&mut self) {
-        console_log!("calculating streak");
-        let current_time = Date::now().as_millis() / 1000;
-        console_log!("current time: {:?}", current_time);
-        let time_since_last_login = current_time - self.profile.last_login;
-        let one_day = 60 * 60 * 24;
-        let two_days = one_day * 2;
-        // let one_hour = 30;
-
-        // if time_since_last_login >= one_hour {
-        //     self.game_state.inventory_aliens += 10;
-        // }
-
-        if time_since_last_login > one_day && time_since_last_login < two_days {
-            self.progress.streak += 1;
-            self.profile.last_login = current_time;
-        } else if time_since_last_login >= two_days {
-            self.progress.streak = 0;
-            self.profile.last_login = current_time;
+    /// Name shown to other players: the username if set, the user id otherwise
+    pub fn display_name(&self) -> &str {
+        self.profile
+            .user_name
+            .as_deref()
+            .unwrap_or(&self.profile.user_id)
+    }
+
+    /// Copy safe to hand out of the DO: the password hash is left out
+    pub fn without_secrets(&self) -> UserData {
+        let mut state = self.clone();
+        state.profile.password = None;
+        state
+    }
+
+    /// Add (or with `revoke`, take back) an admin grant. Balances stop at zero
+    /// and revoking an item the player doesn't hold is a no-op.
+    pub fn apply_grant(&mut self, grant: &Grant, revoke: bool) {
+        let adjust = |value: &mut usize, amount: usize| {
+            *value = if revoke {
+                value.saturating_sub(amount)
+            } else {
+                value.saturating_add(amount)
+            };
+        };
+
+        match grant {
+            Grant::AkaiBalance(amount) => adjust(&mut self.progress.akai_balance, *amount),
+            Grant::SocialScore(amount) => adjust(&mut self.progress.social_score, *amount),
+            Grant::Iq(amount) => adjust(&mut self.progress.iq, *amount),
+            Grant::InventoryAliens(amount) => {
+                adjust(&mut self.game_state.inventory_aliens, *amount)
+            }
+            Grant::PowerUp(kind) => {
+                if !revoke {
+                    self.game_state.power_ups.push(*kind);
+                } else if let Some(i) = self.game_state.power_ups.iter().position(|p| p == kind) {
+                    self.game_state.power_ups.remove(i);
+                }
+            }
+            Grant::Badge(badge) => {
+                let held = self.progress.badges.contains(badge);
+                if !revoke && !held {
+                    self.progress.badges.push(badge.clone());
+                } else if revoke {
+                    self.progress.badges.retain(|b| b != badge);
+                }
+            }
         }
+    }
 
+    /// Streaks follow daily-task completion (see streak.rs); this only
+    /// records when the player was last seen
+    pub fn calculate_last_login(&mut self) {
+        self.profile.last_login = Date::now().as_millis() / 1000;
     }
 }
@@ -5,8 +5,9 @@ use worker::D1Database;
 use worker::*;
 
 use crate::{
-    sql,
-    types::{BadgesKind, GameState, LeagueType, PowerUpKind, PreLabel, UserData, VideoTask},
+    daily_task::{DailyCounter, DAILY_COUNTER_REWARD},
+    streak,
+    types::{BadgesKind, LeagueType, PowerUpKind, UserData},
 };
 
 // Helper function to convert power_ups to JSON for SQLite
@@ -57,9 +58,6 @@ pub fn league_to_string(league: &LeagueType) -> String {
 }
 
 pub async fn is_registered(d1: &D1Database, user_id: &str) -> bool {
-    sql::create_table_if_not_exists(d1)
-        .await
-        .expect("creation failed");
     let stmt = d1.prepare("SELECT 1 FROM user_profile WHERE user_id = ?");
     stmt.bind(&[user_id.into()])
         .expect("bind failed")
@@ -79,6 +77,39 @@ pub fn calculate_product(user_data: &mut UserData) {
     user_data.league = LeagueType::from_product(user_data.progress.product);
 }
 
+/// Count one step towards a daily target, rewarding the player the first time
+/// the target is reached. Does nothing before daily tasks have been generated.
+pub fn advance_daily_counter(user_data: &mut UserData, counter: DailyCounter) {
+    let daily = &mut user_data.daily;
+    let (done, target, completed) = match counter {
+        DailyCounter::Merge => &mut daily.daily_merge,
+        DailyCounter::Annotate => &mut daily.daily_annotate,
+        DailyCounter::PowerUps => &mut daily.daily_powerups,
+    };
+
+    *done += 1;
+    if *target > 0 && *done >= *target && !*completed {
+        *completed = true;
+        daily.total_completed += 1;
+        user_data.progress.social_score += DAILY_COUNTER_REWARD;
+        streak::record_completion(user_data);
+    }
+}
+
+/// Put an alien of `level` in the first empty grid slot, or over the lowest
+/// alien when the grid is full
+pub fn place_alien(user_data: &mut UserData, level: usize) {
+    let aliens = &mut user_data.game_state.active_aliens;
+    let target_index = aliens.iter().position(|&val| val == 0).unwrap_or_else(|| {
+        aliens
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, &val)| val)
+            .map_or(0, |(i, _)| i)
+    });
+    aliens[target_index] = level;
+}
+
 pub fn calculate_king_alien_lvl(user_data: &mut UserData) {
     // Calculate new level: (sum of active aliens / 50) + 1
     let sum: usize = user_data.game_state.active_aliens.iter().sum();
@@ -93,26 +124,7 @@ pub fn calculate_king_alien_lvl(user_data: &mut UserData) {
 
         // Add 5 aliens (lvl - 3)
         for _ in 0..5 {
-            let earned_alien = new_lvl * 10 - 3;
-
-            let mut first_empty_index: Option<usize> = None;
-            let mut min_value = usize::MAX;
-            let mut min_index: usize = 0;
-
-            for (i, &val) in user_data.game_state.active_aliens.iter().enumerate() {
-                if val == 0 && first_empty_index.is_none() {
-                    first_empty_index = Some(i);
-                    break;
-                }
-
-                if val < min_value {
-                    min_value = val;
-                    min_index = i;
-                }
-            }
-
-            let target_index = first_empty_index.unwrap_or(min_index);
-            user_data.game_state.active_aliens[target_index] = earned_alien;
+            place_alien(user_data, new_lvl * 10 - 3);
         }
 
         // Add a random power up
@@ -130,112 +142,13 @@ pub fn calculate_king_alien_lvl(user_data: &mut UserData) {
     }
 }
 
-pub fn give_daily_reward(user_data: &mut UserData, index: usize) {
-    if user_data.daily.total_completed >= 3 && user_data.daily.alien_earned.is_none() && index == 3
-    {
-        let earned_alien = user_data.game_state.king_lvl * 10 - 3;
-        user_data.daily.alien_earned = Some(earned_alien);
-
-        let mut first_empty_index: Option<usize> = None;
-        let mut min_value = usize::MAX;
-        let mut min_index: usize = 0;
-
-        for (i, &val) in user_data.game_state.active_aliens.iter().enumerate() {
-            if val == 0 && first_empty_index.is_none() {
-                first_empty_index = Some(i);
-                break;
-            }
-
-            if val < min_value {
-                min_value = val;
-                min_index = i;
-            }
-        }
-
-        let target_index = first_empty_index.unwrap_or(min_index);
-        user_data.game_state.active_aliens[target_index] = earned_alien;
-        calculate_king_alien_lvl(user_data);
-    }
-
-    if user_data.daily.total_completed >= 5 && user_data.daily.pu_earned.is_none() && index == 5 {
-        let powerups = [
-            PowerUpKind::RowPowerUp,
-            PowerUpKind::ColumnPowerUp,
-            PowerUpKind::NearestSquarePowerUp,
-        ];
-
-        let mut rng = thread_rng();
-        let random_pu = *powerups.choose(&mut rng).unwrap();
-
-        user_data.daily.pu_earned = Some(random_pu);
-        user_data.game_state.power_ups.push(random_pu);
-    }
-}
-
-pub async fn fetch_video_tasks(n: usize, env: &Env) -> Result<Vec<VideoTask>> {
-    println!("{}", 100);
-
-    let url = "https://your-backend/api/get-videos"; // <-- Replace this
-    let payload = serde_json::json!({ "numberOfDatapoints": n }).to_string();
-
-    let req = Request::new_with_init(
-        url,
-        &RequestInit {
-            method: Method::Post,
-            body: Some(payload.into()),
-            headers: {
-                let mut headers = Headers::new();
-                headers.set("Content-Type", "application/json")?;
-                headers
-            },
-            ..Default::default()
-        },
-    )?;
-
-    let mut response = Fetch::Request(req).send().await?;
-    console_log!("{:?}", response);
-    let data: serde_json::Value = response.json().await?;
-    console_log!("{:?}", data);
-    let tasks = data
-        .as_array()
-        .unwrap_or(&vec![])
-        .iter()
-        .map(|item| VideoTask {
-            id: item["_id"].as_str().unwrap_or_default().to_string(),
-            task_id: item["task_id"].as_str().unwrap_or_default().to_string(),
-            media_url: item["mediaUrl"].as_str().unwrap_or_default().to_string(),
-            pre_label: PreLabel {
-                map_position: item["preLabel"]["map_position"]
-                    .as_str()
-                    .unwrap_or_default()
-                    .to_string(),
-                summary: item["preLabel"]["summary"]
-                    .as_str()
-                    .unwrap_or_default()
-                    .to_string(),
-                keywords: item["preLabel"]["keywords"]
-                    .as_array()
-                    .unwrap_or(&vec![])
-                    .iter()
-                    .filter_map(|kw| kw.as_str().map(String::from))
-                    .collect(),
-            },
-            visited: false,
-        })
-        .collect();
-
-    console_log!("{:?}", tasks);
-
-    Ok(tasks)
-}
-
 #[derive(serde::Deserialize)]
 struct UserIdRow {
     user_id: String,
 }
 
 pub async fn find_user_id_by_referral_code(d1: &D1Database, code: &str) -> Result<Option<String>> {
-    let stmt = d1.prepare("SELECT user_id FROM social_data WHERE referal_code = ?");
+    let stmt = d1.prepare("SELECT user_id FROM social_data WHERE referal_code = ? COLLATE NOCASE");
     let res = stmt.bind(&[code.into()])?.first::<UserIdRow>(None).await;
 
     match res {
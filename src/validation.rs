@@ -0,0 +1,240 @@
+// Input rules for player-chosen strings (referral codes, usernames, ...)
+
+use crate::daily_task::{MAX_UTC_OFFSET_MINUTES, MIN_UTC_OFFSET_MINUTES};
+use crate::types::{LabelSubmission, PreLabel, PreLabelVerdict, TimeSpan};
+
+pub const VANITY_CODE_MIN_LEN: usize = 4;
+pub const VANITY_CODE_MAX_LEN: usize = 16;
+
+pub const EMAIL_MAX_LEN: usize = 254;
+pub const PASSWORD_MIN_LEN: usize = 8;
+pub const PASSWORD_MAX_LEN: usize = 128;
+
+pub const USER_NAME_MIN_LEN: usize = 3;
+pub const USER_NAME_MAX_LEN: usize = 20;
+
+pub const LABEL_SUMMARY_MAX_LEN: usize = 2000;
+pub const LABEL_KEYWORD_MAX_LEN: usize = 50;
+pub const LABEL_MAX_KEYWORD_CHANGES: usize = 20;
+pub const LABEL_MAX_TIME_SPANS: usize = 10;
+
+// Names that could be mistaken for staff or system messages, compared case-insensitively
+const RESERVED_USER_NAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "akai",
+    "anonymous",
+    "bot",
+    "everyone",
+    "mod",
+    "moderator",
+    "null",
+    "official",
+    "root",
+    "staff",
+    "support",
+    "system",
+    "undefined",
+];
+
+// Matched as substrings after lowercasing and undoing common digit swaps, so
+// only words that rarely appear inside innocent ones belong here
+const BLOCKED_WORDS: &[&str] = &[
+    "bitch", "bollock", "cunt", "dildo", "fuck", "hitler", "jizz", "nazi", "nigg", "penis", "porn",
+    "pussy", "retard", "shit", "slut", "twat", "vagina", "wank", "whore",
+];
+
+// Also hidden inside innocent words ("grape", "peacock", "analyst"). Short
+// referral codes block them anywhere; usernames only as a whole word.
+const BLOCKED_WHOLE_WORDS: &[&str] = &[
+    "anal", "anus", "arse", "boner", "cock", "dick", "fag", "piss", "rape",
+];
+
+/// Lowercase and map look-alike characters ("sh1t" -> "shit") before matching
+fn normalize_for_filter(input: &str) -> String {
+    input
+        .chars()
+        .filter_map(|c| match c.to_ascii_lowercase() {
+            '0' => Some('o'),
+            '1' | '!' => Some('i'),
+            '3' => Some('e'),
+            '4' | '@' => Some('a'),
+            '5' | '$' => Some('s'),
+            '7' => Some('t'),
+            '_' | '-' | '.' | ' ' => None,
+            c => Some(c),
+        })
+        .collect()
+}
+
+pub fn contains_profanity(input: &str) -> bool {
+    let normalized = normalize_for_filter(input);
+    BLOCKED_WORDS
+        .iter()
+        .chain(BLOCKED_WHOLE_WORDS)
+        .any(|word| normalized.contains(word))
+}
+
+/// Like `contains_profanity`, but `BLOCKED_WHOLE_WORDS` only match a whole
+/// underscore-separated part of the name, so "grape_fan" is fine and "rape_fan" isn't
+fn user_name_has_blocked_word(name: &str) -> bool {
+    let normalized = normalize_for_filter(name);
+    BLOCKED_WORDS.iter().any(|word| normalized.contains(word))
+        || name.split('_').any(|part| {
+            let part = normalize_for_filter(part);
+            BLOCKED_WHOLE_WORDS.iter().any(|word| part == *word)
+        })
+}
+
+/// Check a player-chosen referral code. Returns the reason it was rejected.
+pub fn validate_vanity_code(code: &str) -> Result<(), &'static str> {
+    if code.len() < VANITY_CODE_MIN_LEN || code.len() > VANITY_CODE_MAX_LEN {
+        return Err("Referral code must be between 4 and 16 characters");
+    }
+    if !code.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err("Referral code may only contain letters and digits");
+    }
+    if contains_profanity(code) {
+        return Err("Referral code contains a blocked word");
+    }
+    Ok(())
+}
+
+/// Check a username against the charset, length, reserved-word and profanity rules
+pub fn validate_user_name(name: &str) -> Result<(), &'static str> {
+    if name.len() < USER_NAME_MIN_LEN || name.len() > USER_NAME_MAX_LEN {
+        return Err("Username must be between 3 and 20 characters");
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err("Username may only contain letters, digits and underscores");
+    }
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err("Username must start with a letter");
+    }
+    if RESERVED_USER_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(name))
+    {
+        return Err("Username is reserved");
+    }
+    if user_name_has_blocked_word(name) {
+        return Err("Username contains a blocked word");
+    }
+    Ok(())
+}
+
+/// Pragmatic address check: one '@', a non-empty local part and a dotted domain
+pub fn validate_email(email: &str) -> Result<(), &'static str> {
+    const INVALID: &str = "Invalid email address";
+
+    if email.len() > EMAIL_MAX_LEN || email.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(INVALID);
+    }
+    let Some((local, domain)) = email.split_once('@') else {
+        return Err(INVALID);
+    };
+    if local.is_empty() || local.len() > 64 || domain.contains('@') {
+        return Err(INVALID);
+    }
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    let valid_label = |label: &&str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if labels.len() < 2 || !labels.iter().all(valid_label) {
+        return Err(INVALID);
+    }
+    Ok(())
+}
+
+pub fn validate_password(password: &str) -> Result<(), &'static str> {
+    if password.chars().count() < PASSWORD_MIN_LEN {
+        return Err("Password must be at least 8 characters");
+    }
+    if password.chars().count() > PASSWORD_MAX_LEN {
+        return Err("Password must be at most 128 characters");
+    }
+    Ok(())
+}
+
+/// Check a label against the datapoint's prelabel. Returns the reason it was rejected.
+pub fn validate_label_submission(
+    submission: &LabelSubmission,
+    pre_label: &PreLabel,
+) -> Result<(), &'static str> {
+    match submission.verdict {
+        PreLabelVerdict::Accept if submission.has_corrections() => {
+            return Err("An accepted prelabel cannot carry corrections")
+        }
+        PreLabelVerdict::Reject if !submission.has_corrections() => {
+            return Err("A rejected prelabel needs at least one correction")
+        }
+        _ => {}
+    }
+
+    if let Some(summary) = &submission.summary {
+        if summary.trim().is_empty() {
+            return Err("Summary must not be empty");
+        }
+        if summary.chars().count() > LABEL_SUMMARY_MAX_LEN {
+            return Err("Summary must be at most 2000 characters");
+        }
+    }
+
+    let (added, removed) = (&submission.keywords_added, &submission.keywords_removed);
+    if added.len() + removed.len() > LABEL_MAX_KEYWORD_CHANGES {
+        return Err("At most 20 keyword changes per label");
+    }
+    let in_pre_label = |kw: &str| {
+        pre_label
+            .keywords
+            .iter()
+            .any(|existing| existing.eq_ignore_ascii_case(kw.trim()))
+    };
+    for (i, kw) in added.iter().enumerate() {
+        let trimmed = kw.trim();
+        if trimmed.is_empty() || trimmed.chars().count() > LABEL_KEYWORD_MAX_LEN {
+            return Err("Keywords must be between 1 and 50 characters");
+        }
+        if in_pre_label(trimmed) {
+            return Err("Added keyword is already in the prelabel");
+        }
+        if added[..i]
+            .iter()
+            .any(|other| other.trim().eq_ignore_ascii_case(trimmed))
+        {
+            return Err("Keyword added twice");
+        }
+    }
+    if !removed.iter().all(|kw| in_pre_label(kw)) {
+        return Err("Removed keyword is not in the prelabel");
+    }
+
+    if let Some(pos) = submission.map_position {
+        if !(-90.0..=90.0).contains(&pos.lat) || !(-180.0..=180.0).contains(&pos.lng) {
+            return Err("Map position is out of range");
+        }
+    }
+
+    if submission.time_spans.len() > LABEL_MAX_TIME_SPANS {
+        return Err("At most 10 time spans per label");
+    }
+    let valid_span = |span: &TimeSpan| {
+        span.start_secs >= 0.0 && span.start_secs < span.end_secs && span.end_secs.is_finite()
+    };
+    if !submission.time_spans.iter().all(valid_span) {
+        return Err("Time spans must start at or after 0 and end after they start");
+    }
+    Ok(())
+}
+
+/// UTC offsets run from -12:00 to +14:00 in quarter hours
+pub fn validate_utc_offset(minutes: i32) -> Result<(), &'static str> {
+    if !(MIN_UTC_OFFSET_MINUTES..=MAX_UTC_OFFSET_MINUTES).contains(&minutes) || minutes % 15 != 0 {
+        return Err("Timezone offset must be a multiple of 15 minutes between -720 and 840");
+    }
+    Ok(())
+}
@@ -0,0 +1,215 @@
+// Shared pool of video datapoints fetched ahead of time by the cron, so
+// generating a player's daily tasks is a D1 read instead of a call to the
+// labeling backend. Each datapoint goes to at most VIDEO_TASK_MAX_ASSIGNMENTS
+// players and never twice to the same player.
+use chrono::Utc;
+use serde::Deserialize;
+use worker::{D1Database, Env, Result};
+
+use crate::labeling_client::{LabelingBackend, LabelingClient};
+use crate::types::VideoTask;
+
+// Unassignable-for-good datapoints are dropped after this long
+const POOL_RETENTION_SECS: i64 = 7 * 24 * 60 * 60;
+
+pub const DEFAULT_POOL_TARGET: usize = 200;
+pub const DEFAULT_MAX_ASSIGNMENTS: usize = 10;
+
+// Largest batch asked of the labeling backend in one call
+const MAX_FETCH_BATCH: usize = 100;
+
+fn var_or(env: &Env, name: &str, default: usize) -> usize {
+    env.var(name)
+        .ok()
+        .and_then(|v| v.to_string().parse::<usize>().ok())
+        .unwrap_or(default)
+}
+
+/// Assignable datapoints the cron keeps in stock (VIDEO_POOL_TARGET)
+pub fn pool_target(env: &Env) -> usize {
+    var_or(env, "VIDEO_POOL_TARGET", DEFAULT_POOL_TARGET)
+}
+
+/// Players a datapoint is handed to (VIDEO_TASK_MAX_ASSIGNMENTS)
+pub fn max_assignments(env: &Env) -> usize {
+    var_or(env, "VIDEO_TASK_MAX_ASSIGNMENTS", DEFAULT_MAX_ASSIGNMENTS).max(1)
+}
+
+#[derive(Deserialize)]
+struct PoolRow {
+    datapoint_id: String,
+    task_id: String,
+    media_url: String,
+    pre_label: String,
+    difficulty: f64,
+}
+
+impl PoolRow {
+    fn into_task(self) -> Option<VideoTask> {
+        Some(VideoTask {
+            id: self.datapoint_id,
+            task_id: self.task_id,
+            media_url: self.media_url,
+            pre_label: serde_json::from_str(&self.pre_label).ok()?,
+            visited: false,
+            difficulty: self.difficulty,
+        })
+    }
+}
+
+pub async fn available_count(d1: &D1Database, env: &Env) -> Result<usize> {
+    let count = d1
+        .prepare("SELECT COUNT(*) AS available FROM video_task_pool WHERE assigned_count < ?")
+        .bind(&[max_assignments(env).into()])?
+        .first::<usize>(Some("available"))
+        .await?;
+    Ok(count.unwrap_or(0))
+}
+
+/// Add datapoints to the pool; ones already in it are left alone
+pub async fn add_to_pool(d1: &D1Database, tasks: &[VideoTask]) -> Result<()> {
+    if tasks.is_empty() {
+        return Ok(());
+    }
+    let now = Utc::now().timestamp() as f64;
+    let statements = tasks
+        .iter()
+        .map(|task| {
+            d1.prepare(
+                "INSERT OR IGNORE INTO video_task_pool (datapoint_id, task_id, media_url, pre_label, difficulty, fetched_at, assigned_count)
+                 VALUES (?, ?, ?, ?, ?, ?, 0)",
+            )
+            .bind(&[
+                task.id.as_str().into(),
+                task.task_id.as_str().into(),
+                task.media_url.as_str().into(),
+                serde_json::to_string(&task.pre_label)?.into(),
+                task.difficulty.into(),
+                now.into(),
+            ])
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    d1.batch(statements).await?;
+    Ok(())
+}
+
+/// Ask the labeling backend for up to `count` datapoints and pool them
+async fn fetch_into_pool(d1: &D1Database, env: &Env, count: usize) -> Result<usize> {
+    let tasks = LabelingClient::from_env(env)
+        .fetch_video_tasks(count.min(MAX_FETCH_BATCH))
+        .await?;
+    add_to_pool(d1, &tasks).await?;
+    Ok(tasks.len())
+}
+
+/// Top the pool back up to `pool_target` and drop stale datapoints. Run by the cron.
+pub async fn refill_pool(d1: &D1Database, env: &Env, rid: &str) -> Result<()> {
+    let cutoff = (Utc::now().timestamp() - POOL_RETENTION_SECS) as f64;
+    d1.prepare("DELETE FROM video_task_pool WHERE fetched_at < ?")
+        .bind(&[cutoff.into()])?
+        .run()
+        .await?;
+
+    let available = available_count(d1, env).await?;
+    let mut missing = pool_target(env).saturating_sub(available);
+    let mut fetched = 0;
+    while missing > 0 {
+        let got = fetch_into_pool(d1, env, missing).await?;
+        if got == 0 {
+            break;
+        }
+        fetched += got;
+        missing = missing.saturating_sub(got);
+    }
+
+    log_info!(rid, "video_pool.refilled", "available" => available, "fetched" => fetched);
+    Ok(())
+}
+
+/// Difficulty of a pooled datapoint, `None` once it has left the pool
+pub async fn difficulty(d1: &D1Database, datapoint_id: &str) -> Result<Option<f64>> {
+    d1.prepare("SELECT difficulty FROM video_task_pool WHERE datapoint_id = ?")
+        .bind(&[datapoint_id.into()])?
+        .first::<f64>(Some("difficulty"))
+        .await
+}
+
+async fn take_from_pool(
+    d1: &D1Database,
+    env: &Env,
+    user_id: &str,
+    skill: f64,
+    count: usize,
+) -> Result<Vec<VideoTask>> {
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    // Nearest in difficulty to the player's skill, then closest to full so
+    // datapoints reach enough labels to be scored
+    let rows = d1
+        .prepare(
+            "SELECT datapoint_id, task_id, media_url, pre_label, difficulty FROM video_task_pool
+             WHERE assigned_count < ?
+               AND datapoint_id NOT IN (SELECT datapoint_id FROM video_task_assignments WHERE user_id = ?)
+             ORDER BY ABS(difficulty - ?), assigned_count DESC, fetched_at
+             LIMIT ?",
+        )
+        .bind(&[
+            max_assignments(env).into(),
+            user_id.into(),
+            skill.into(),
+            count.into(),
+        ])?
+        .all()
+        .await?
+        .results::<PoolRow>()?;
+
+    let tasks: Vec<VideoTask> = rows.into_iter().filter_map(PoolRow::into_task).collect();
+    if tasks.is_empty() {
+        return Ok(tasks);
+    }
+
+    let now = Utc::now().timestamp() as f64;
+    let mut statements = Vec::with_capacity(tasks.len() * 2);
+    for task in &tasks {
+        statements.push(
+            d1.prepare(
+                "INSERT OR IGNORE INTO video_task_assignments (user_id, datapoint_id, assigned_at)
+                 VALUES (?, ?, ?)",
+            )
+            .bind(&[user_id.into(), task.id.as_str().into(), now.into()])?,
+        );
+        statements.push(
+            d1.prepare(
+                "UPDATE video_task_pool SET assigned_count = assigned_count + 1 WHERE datapoint_id = ?",
+            )
+            .bind(&[task.id.as_str().into()])?,
+        );
+    }
+    d1.batch(statements).await?;
+
+    Ok(tasks)
+}
+
+/// Hand `count` datapoints the player has never had, matched to their
+/// annotation skill. If the pool runs short the player gets fewer; whatever is
+/// missing is retried when the player next checks their daily tasks.
+pub async fn assign_tasks(
+    d1: &D1Database,
+    env: &Env,
+    user_id: &str,
+    skill: f64,
+    count: usize,
+    rid: &str,
+) -> Result<Vec<VideoTask>> {
+    let tasks = take_from_pool(d1, env, user_id, skill, count).await?;
+
+    // Refilling is the cron's job, so the DO never waits on the labeling backend
+    if tasks.len() < count {
+        log_warn!(rid, "video_pool.short", "user_id" => user_id, "wanted" => count, "got" => tasks.len());
+    }
+
+    Ok(tasks)
+}